@@ -0,0 +1,168 @@
+//! Signed requests of order owners.
+//!
+//! A mutating request carries the signature of the address in its path over
+//! `method \n path \n body hash \n timestamp \n nonce`, see `message`.
+use kvdb::KeyValueDB;
+use kvdb_rocksdb::Database;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use std::str;
+use std::sync::Mutex;
+
+use crate::constants::NONCE_COL;
+use crate::crypto::{Crypto, Sr25519, Ed25519};
+use crate::error::{MinerError, MinerErrorKind, Result};
+
+
+/// seconds a signed request stays valid, nonces are remembered as long
+pub const SIGNATURE_WINDOW: i64 = 300;
+
+pub const SIGNATURE_HEADER: &str = "X-Ipse-Signature";
+pub const CRYPTO_HEADER: &str = "X-Ipse-Crypto";
+pub const TIMESTAMP_HEADER: &str = "X-Ipse-Timestamp";
+pub const NONCE_HEADER: &str = "X-Ipse-Nonce";
+pub const BODY_HASH_HEADER: &str = "X-Ipse-Body-Hash";
+
+lazy_static! {
+    /// held from the lookup of a nonce to its write, so a replay racing the
+    /// first request cannot pass the lookup too
+    static ref NONCES: Mutex<()> = Mutex::new(());
+}
+
+/// The signed parts of a request.
+#[derive(Debug, Clone)]
+pub struct Signed {
+    pub address: String,
+    pub method: String,
+    pub path: String,
+    /// hex blake2b-256 of the request body
+    pub body_hash: String,
+    pub timestamp: i64,
+    pub nonce: String,
+    /// `sr25519` (default) or `ed25519`
+    pub crypto: String,
+    pub signature: Vec<u8>,
+}
+
+pub fn unauthorized(message: impl ToString) -> MinerError {
+    MinerError::new(MinerErrorKind::Unauthorized(message.to_string()))
+}
+
+/// the bytes a client signs
+pub fn message(method: &str, path: &str, body_hash: &str, timestamp: i64, nonce: &str) -> Vec<u8> {
+    format!("{}\n{}\n{}\n{}\n{}", method.to_uppercase(), path, body_hash.to_lowercase(), timestamp, nonce).into_bytes()
+}
+
+/// decode a hex signature, with or without `0x`
+pub fn decode_signature(signature: &str) -> Result<Vec<u8>> {
+    hex::decode(signature.trim_start_matches("0x")).map_err(|_| unauthorized("signature is not hex encoded"))
+}
+
+impl Signed {
+    /// check the signature and the timestamp, without touching the nonce store
+    pub fn verify(&self) -> Result<()> {
+        let now = Utc::now().timestamp();
+        if (now - self.timestamp).abs() > SIGNATURE_WINDOW {
+            return Err(unauthorized("request timestamp is outside of the allowed window"));
+        }
+        if self.nonce.is_empty() || self.nonce.len() > 64 {
+            return Err(unauthorized("request nonce must have 1 to 64 characters"));
+        }
+
+        let message = message(&self.method, &self.path, &self.body_hash, self.timestamp, &self.nonce);
+        let valid = match self.crypto.as_str() {
+            "sr25519" => Sr25519::verify(&self.signature, &message, &self.address),
+            "ed25519" => Ed25519::verify(&self.signature, &message, &self.address),
+            crypto => return Err(unauthorized(format!("unsupported crypto type `{}`", crypto))),
+        };
+        if !valid {
+            return Err(unauthorized("invalid signature"));
+        }
+        Ok(())
+    }
+
+    /// remember the nonce of a verified request, rejecting a replay
+    pub fn consume_nonce(&self, db: &Database) -> Result<()> {
+        let key = [self.address.as_str(), self.nonce.as_str()].concat();
+        let _nonces = NONCES.lock().unwrap();
+        if db.get(NONCE_COL, key.as_bytes())?.is_some() {
+            return Err(unauthorized("request nonce was already used"));
+        }
+
+        let now = Utc::now().timestamp();
+        let mut batch = db.transaction();
+
+        // forget the nonces of this address that can no longer be replayed
+        for (old, seen) in db.iter_with_prefix(NONCE_COL, self.address.as_bytes()) {
+            let expired = str::from_utf8(&seen).ok()
+                .and_then(|t| t.parse::<i64>().ok())
+                .map_or(true, |t| now - t > SIGNATURE_WINDOW);
+            if expired {
+                batch.delete(NONCE_COL, &old);
+            }
+        }
+        batch.put(NONCE_COL, key.as_bytes(), now.to_string().as_bytes());
+
+        db.write(batch)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use kvdb_rocksdb::DatabaseConfig;
+    use sp_core::{sr25519, Pair};
+    use tempdir::TempDir;
+    use crate::constants::META_COL;
+    use crate::crypto::Ss58Codec;
+
+    fn signed(pair: &sr25519::Pair, body_hash: &str) -> Signed {
+        let timestamp = Utc::now().timestamp();
+        let message = message("DELETE", "/api/v0/order/x/y", body_hash, timestamp, "n1");
+        Signed {
+            address: pair.public().to_ss58check(),
+            method: "DELETE".to_owned(),
+            path: "/api/v0/order/x/y".to_owned(),
+            body_hash: body_hash.to_owned(),
+            timestamp,
+            nonce: "n1".to_owned(),
+            crypto: "sr25519".to_owned(),
+            signature: pair.sign(&message).0.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let (pair, _) = sr25519::Pair::generate();
+
+        let request = signed(&pair, "00");
+        assert!(request.verify().is_ok());
+
+        let mut tampered = request.clone();
+        tampered.body_hash = "01".to_owned();
+        assert!(tampered.verify().is_err());
+
+        let mut expired = request.clone();
+        expired.timestamp -= SIGNATURE_WINDOW + 1;
+        assert!(expired.verify().is_err());
+    }
+
+    #[test]
+    fn test_consume_nonce() {
+        let tempdir = TempDir::new("").unwrap();
+        let db = Arc::new(Database::open(&DatabaseConfig::with_columns(META_COL), tempdir.path().to_str().unwrap()).unwrap());
+        let (pair, _) = sr25519::Pair::generate();
+
+        let request = signed(&pair, "00");
+        let replays: Vec<_> = (0..4).map(|_| {
+            let (db, request) = (db.clone(), request.clone());
+            thread::spawn(move || request.consume_nonce(&db).is_ok())
+        }).collect();
+        let accepted = replays.into_iter().filter(|r| r.join().unwrap()).count();
+        assert_eq!(1, accepted);
+    }
+}
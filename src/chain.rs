@@ -10,12 +10,13 @@ use sp_runtime::{
 
 
 use sub_runtime::ipse::{Order, Miner};
-use substrate_subxt::{balances::{Balances, AccountData, BalancesEventsDecoder}, module, PairSigner, extrinsic::{DefaultExtra}, Runtime, Client, system::{System, SystemEventsDecoder}, Call, Store};
-use sp_core::{sr25519::Pair, Pair as PairT};
+use substrate_subxt::{balances::{Balances, AccountData, BalancesEventsDecoder}, module, PairSigner, extrinsic::{DefaultExtra}, Runtime, Client, system::{System, SystemEventsDecoder, AccountStoreExt}, Call, Store};
+use sp_core::sr25519::Pair;
 
 use frame_support::sp_runtime::SaturatedConversion;
 
 use crate::error::MinerError;
+use crate::extrinsic;
 use crate::settings::Settings;
 use sp_core::crypto::{Ss58Codec, AccountId32};
 
@@ -57,6 +58,18 @@ pub struct RegisterMinerCall<T: Ipse> {
     pub unit_price: Balance,
 }
 
+#[derive(Encode, Call)]
+pub struct UpdateMinerCall<T: Ipse> {
+    pub _runtime: PhantomData<T>,
+    pub nickname: Vec<u8>,
+    pub region: Vec<u8>,
+    pub url: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub income_address: AccountId,
+    pub capacity: u64,
+    pub unit_price: Balance,
+}
+
 #[derive(Encode, Call)]
 pub struct ConfirmOrderCall<T: Ipse> {
     pub _runtime: PhantomData<T>,
@@ -112,14 +125,15 @@ impl Timestamp for IpseRuntime {
 }
 
 
-pub async fn register_miner(settings: &Settings, pair: Pair, sub_client: Client<IpseRuntime>) -> Result<sp_core::H256, MinerError> {
+/// signer of the miner extrinsics, the nonce is fetched from the chain unless set
+pub type Signer = PairSigner<IpseRuntime, Pair>;
 
-    // https://stackoverflow.com/questions/56081117/how-do-you-convert-between-substrate-specific-types-and-rust-primitive-types
+pub async fn register_miner(settings: &Settings, signer: &Signer, sub_client: &Client<IpseRuntime>) -> Result<sp_core::H256, MinerError> {
 
-    let signer = PairSigner::new(pair);
+    // https://stackoverflow.com/questions/56081117/how-do-you-convert-between-substrate-specific-types-and-rust-primitive-types
 
     let res = sub_client.register_miner(
-        &signer,
+        signer,
         settings.miner.nickname.as_bytes().to_vec(),
         settings.miner.region.as_bytes().to_vec(),
         settings.miner.url.as_bytes().to_vec(),
@@ -133,21 +147,145 @@ pub async fn register_miner(settings: &Settings, pair: Pair, sub_client: Client<
     Ok(res)
 }
 
-pub async fn confirm_order(pair: Pair, sub_client: Client<IpseRuntime>, order_id: u64, url: String) -> Result<sp_core::H256, MinerError> {
-    let signer = PairSigner::new(pair);
+/// Whether the runtime of the node has the `update_miner` dispatchable, a
+/// runtime without it only lets a miner register.
+pub fn can_update_miner(sub_client: &Client<IpseRuntime>) -> bool {
+    sub_client.metadata().module_with_calls("Ipse")
+        .and_then(|module| module.call("update_miner", ()))
+        .is_ok()
+}
+
+pub async fn update_miner(settings: &Settings, signer: &Signer, sub_client: &Client<IpseRuntime>) -> Result<sp_core::H256, MinerError> {
+    let res = sub_client.update_miner(
+        signer,
+        settings.miner.nickname.as_bytes().to_vec(),
+        settings.miner.region.as_bytes().to_vec(),
+        settings.miner.url.as_bytes().to_vec(),
+        settings.miner.public_key.as_bytes().to_vec(),
+        AccountId32::from_string(settings.miner.income_address.as_str())?,
+        settings.miner.capacity as u64,
+        settings.miner.unit_price.saturated_into::<Balance>(),
+    ).await?;
+
+    Ok(res)
+}
+
+/// One field of the on-chain miner profile differing from the settings.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ProfileDiff {
+    pub field: &'static str,
+    pub chain: String,
+    pub settings: String,
+}
+
+/// fields of the registered `miner` differing from `settings.miner`
+pub fn profile_diff(miner: &Miner<AccountId, Balance>, settings: &Settings) -> Vec<ProfileDiff> {
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    // compared as accounts, the settings may hold another ss58 format of it
+    let income_address = match AccountId32::from_string(&settings.miner.income_address) {
+        Ok(income) if income == miner.income_address => miner.income_address.to_ss58check(),
+        _ => settings.miner.income_address.clone(),
+    };
+    let fields = vec![
+        ("nickname", text(&miner.nickname), settings.miner.nickname.clone()),
+        ("region", text(&miner.region), settings.miner.region.clone()),
+        ("url", text(&miner.url), settings.miner.url.clone()),
+        ("public_key", text(&miner.public_key), settings.miner.public_key.clone()),
+        ("income_address", miner.income_address.to_ss58check(), income_address),
+        ("capacity", miner.capacity.to_string(), settings.miner.capacity.to_string()),
+        ("unit_price", miner.unit_price.to_string(), settings.miner.unit_price.to_string()),
+    ];
+
+    fields.into_iter()
+        .filter(|(_, chain, settings)| chain != settings)
+        .map(|(field, chain, settings)| ProfileDiff { field, chain, settings })
+        .collect()
+}
+
+/// What bringing the on-chain profile in line with the settings takes,
+/// `extrinsic` is the id of the queued call, see `extrinsic`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Registration {
+    Unchanged,
+    Register { extrinsic: Option<u64> },
+    Update { diffs: Vec<ProfileDiff>, extrinsic: Option<u64> },
+}
+
+impl Registration {
+    /// The call bringing the profile in line with the settings, `None` when
+    /// it matches. Fails for an update the runtime has no dispatchable for.
+    pub fn call(&self, sub_client: &Client<IpseRuntime>) -> Result<Option<extrinsic::Call>, MinerError> {
+        match self {
+            Registration::Unchanged => Ok(None),
+            Registration::Register { .. } => Ok(Some(extrinsic::Call::RegisterMiner)),
+            Registration::Update { .. } if can_update_miner(sub_client) => Ok(Some(extrinsic::Call::UpdateMiner)),
+            Registration::Update { .. } => Err(MinerError::msg("the runtime of the node has no `update_miner`, the profile can not be updated")),
+        }
+    }
+
+    /// record the id of the queued call
+    pub fn queued(&mut self, id: u64) {
+        match self {
+            Registration::Register { extrinsic } | Registration::Update { extrinsic, .. } => *extrinsic = Some(id),
+            Registration::Unchanged => {}
+        }
+    }
+}
+
+/// Compare the on-chain profile of `account` with the settings. Nothing is
+/// submitted here, the call of the result goes through the extrinsic queue.
+pub async fn registration(settings: &Settings, account: AccountId, sub_client: &Client<IpseRuntime>) -> Result<Registration, MinerError> {
+    match miner(sub_client, account).await? {
+        None => Ok(Registration::Register { extrinsic: None }),
+        Some(miner) => {
+            let diffs = profile_diff(&miner, settings);
+            if diffs.is_empty() {
+                Ok(Registration::Unchanged)
+            } else {
+                Ok(Registration::Update { diffs, extrinsic: None })
+            }
+        }
+    }
+}
+
+pub async fn confirm_order(signer: &Signer, sub_client: &Client<IpseRuntime>, order_id: u64, url: String) -> Result<sp_core::H256, MinerError> {
     let res = sub_client.confirm_order(
-        &signer,
+        signer,
         order_id,
         url.into_bytes(),
     ).await?;
     Ok(res)
 }
 
-pub async fn delete_order(pair: Pair, sub_client: Client<IpseRuntime>, order_id: u64) -> Result<sp_core::H256, MinerError> {
-    let signer = PairSigner::new(pair);
+pub async fn delete_order(signer: &Signer, sub_client: &Client<IpseRuntime>, order_id: u64) -> Result<sp_core::H256, MinerError> {
     let res = sub_client.delete_order(
-        &signer,
+        signer,
         order_id,
     ).await?;
     Ok(res)
-}
\ No newline at end of file
+}
+
+/// nonce of `account` at the block `at`, the best block when `None`
+pub async fn account_nonce(sub_client: &Client<IpseRuntime>, account: &AccountId, at: Option<sp_core::H256>) -> Result<u32, MinerError> {
+    let info = sub_client.account(account, at).await?;
+    Ok(info.nonce)
+}
+
+/// hash of the last finalized block
+pub async fn finalized_head(sub_client: &Client<IpseRuntime>) -> Result<sp_core::H256, MinerError> {
+    let head = sub_client.finalized_head().await?;
+    Ok(head)
+}
+
+/// the registration of `account`, `None` when it is not a miner
+pub async fn miner(sub_client: &Client<IpseRuntime>, account: AccountId) -> Result<Option<Miner<AccountId, Balance>>, MinerError> {
+    let miner = sub_client.miners_store(account, None).await?;
+    Ok(miner)
+}
+
+/// every order of the chain, the index of an order is its id
+pub async fn orders(sub_client: &Client<IpseRuntime>) -> Result<Vec<Order<AccountId, Balance>>, MinerError> {
+    let orders = sub_client.orders_store(None).await?;
+    Ok(orders)
+}
@@ -1,6 +1,12 @@
 use clap::{crate_authors, crate_description, crate_version, App, AppSettings, Arg, SubCommand};
 
 
+fn json_arg() -> Arg<'static, 'static> {
+    Arg::with_name("json")
+        .long("json")
+        .help("Print the outcome as json")
+}
+
 pub fn build_cli() -> App<'static, 'static> {
     App::new("miner")
         .version(crate_version!())
@@ -67,5 +73,103 @@ pub fn build_cli() -> App<'static, 'static> {
                         .default_value("all")
                         .help("scheduling tasks  for miner")
                 ]),
+            SubCommand::with_name("admin-token")
+                .about("Print the bearer token of the admin api"),
+            SubCommand::with_name("verify-proof")
+                .about("Check a storage challenge answer against the merkle root kept at upload time")
+                .args(&[
+                    Arg::with_name("proof")
+                        .required(true)
+                        .help("Json answer of the challenge endpoint, `-` reads stdin"),
+                    Arg::with_name("merkle-root")
+                        .long("merkle-root")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Hex merkle root returned by the upload"),
+                    Arg::with_name("hash")
+                        .long("hash")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Ipfs hash of the challenged content"),
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Hex seed sent with the challenge"),
+                    Arg::with_name("count")
+                        .long("count")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Number of chunks asked by the challenge"),
+                    Arg::with_name("miner")
+                        .short("m")
+                        .long("miner")
+                        .takes_value(true)
+                        .help("Address of the miner that must have signed the answer"),
+                ]),
+            SubCommand::with_name("register")
+                .about("Register the miner on chain, or update its profile when it differs from the config")
+                .args(&[
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only show what differs, submit nothing"),
+                    json_arg(),
+                ]),
+            SubCommand::with_name("chain")
+                .about("Inspect and fix the on-chain state of the miner")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommands(vec![
+                    SubCommand::with_name("info")
+                        .about("Show the miner account, its balance and on-chain profile")
+                        .arg(json_arg()),
+                    SubCommand::with_name("orders")
+                        .about("List the on-chain orders")
+                        .args(&[
+                            Arg::with_name("mine")
+                                .long("mine")
+                                .help("Only the orders assigned to the miner"),
+                            json_arg(),
+                        ]),
+                    SubCommand::with_name("confirm")
+                        .about("Confirm an order with the miner url through the extrinsic queue")
+                        .args(&[
+                            Arg::with_name("order_id")
+                                .required(true)
+                                .help("Id of the on-chain order"),
+                            json_arg(),
+                        ]),
+                    SubCommand::with_name("delete")
+                        .about("Delete an on-chain order through the extrinsic queue")
+                        .args(&[
+                            Arg::with_name("order_id")
+                                .required(true)
+                                .help("Id of the on-chain order"),
+                            json_arg(),
+                        ]),
+                    SubCommand::with_name("queue")
+                        .about("List the queued and submitted extrinsics")
+                        .arg(json_arg()),
+                ]),
+            SubCommand::with_name("reconcile")
+                .about("Compare the local orders and pins with the chain orders of the miner")
+                .args(&[
+                    Arg::with_name("repin")
+                        .long("repin")
+                        .help("Pin the content of orders the node lost"),
+                    Arg::with_name("unpin")
+                        .long("unpin")
+                        .requires("confirm-unpin")
+                        .help("Unpin the content no local or chain order holds"),
+                    Arg::with_name("confirm-unpin")
+                        .long("confirm-unpin")
+                        .help("Confirm the node pins nothing but the content of this miner, required by --unpin"),
+                    Arg::with_name("mark")
+                        .long("mark")
+                        .help("Record the chain ids of unlinked orders and drop the ids of orphaned ones"),
+                    Arg::with_name("fix")
+                        .long("fix")
+                        .help("Apply every fix but --unpin"),
+                    json_arg(),
+                ]),
         ])
 }
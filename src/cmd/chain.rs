@@ -0,0 +1,225 @@
+use chrono::Utc;
+use futures::executor;
+use serde::Serialize;
+use sp_core::Pair as PairT;
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use substrate_subxt::system::AccountStoreExt;
+use yansi::Paint;
+
+use crate::chain;
+use crate::confirm::{self, Confirmation, State};
+use crate::error::{MinerError, Result};
+use crate::extrinsic::{self, Call, Extrinsic};
+use crate::pricing;
+use crate::settings::{Settings, kv_database, sub_client, miner_pair};
+
+
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// The miner account and its on-chain profile.
+#[derive(Serialize, Debug)]
+struct Info {
+    account: String,
+    chain: String,
+    balance: String,
+    registered: bool,
+    nickname: Option<String>,
+    region: Option<String>,
+    url: Option<String>,
+    public_key: Option<String>,
+    income_address: Option<String>,
+    capacity: Option<u64>,
+    unit_price: Option<String>,
+    /// profile fields differing from the config
+    diffs: Vec<chain::ProfileDiff>,
+}
+
+pub fn info(settings: &Settings, as_json: bool) -> Result<()> {
+    let sub_client = sub_client(settings)?;
+    let account = AccountId32::from(miner_pair(settings)?.public());
+    let miner = executor::block_on(chain::miner(&sub_client, account.clone()))?;
+    let balance = executor::block_on(sub_client.account(&account, None))?.data.free;
+
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    let info = Info {
+        account: account.to_ss58check(),
+        chain: settings.chain.url.clone(),
+        balance: balance.to_string(),
+        registered: miner.is_some(),
+        nickname: miner.as_ref().map(|m| text(&m.nickname)),
+        region: miner.as_ref().map(|m| text(&m.region)),
+        url: miner.as_ref().map(|m| text(&m.url)),
+        public_key: miner.as_ref().map(|m| text(&m.public_key)),
+        income_address: miner.as_ref().map(|m| m.income_address.to_ss58check()),
+        capacity: miner.as_ref().map(|m| m.capacity),
+        unit_price: miner.as_ref().map(|m| m.unit_price.to_string()),
+        diffs: miner.as_ref().map(|m| chain::profile_diff(m, settings)).unwrap_or_default(),
+    };
+    if as_json {
+        return print_json(&info);
+    }
+
+    println!("{} {}", Paint::blue("Account:").bold(), info.account);
+    println!("{} {}", Paint::blue("Chain:").bold(), info.chain);
+    println!("{} {}", Paint::blue("Balance:").bold(), pricing::format_balance(balance, &settings.chain));
+    if !info.registered {
+        println!("{}", Paint::yellow("Not registered, run `miner chain register`").bold());
+        return Ok(());
+    }
+    let none = || "".to_owned();
+    println!("{} {}", Paint::blue("Nickname:").bold(), info.nickname.unwrap_or_else(none));
+    println!("{} {}", Paint::blue("Region:").bold(), info.region.unwrap_or_else(none));
+    println!("{} {}", Paint::blue("Url:").bold(), info.url.unwrap_or_else(none));
+    println!("{} {}", Paint::blue("Public key:").bold(), info.public_key.unwrap_or_else(none));
+    println!("{} {}", Paint::blue("Income address:").bold(), info.income_address.unwrap_or_else(none));
+    println!("{} {}", Paint::blue("Capacity:").bold(), info.capacity.unwrap_or_default());
+    println!("{} {}", Paint::blue("Unit price:").bold(), info.unit_price.unwrap_or_else(none));
+    for diff in info.diffs.iter() {
+        println!("{} {} is {} on chain, {} in the config", Paint::yellow("Differs:").bold(), diff.field, diff.chain, diff.settings);
+    }
+    Ok(())
+}
+
+/// One on-chain order.
+#[derive(Serialize, Debug)]
+struct OrderRow {
+    order_id: u64,
+    hash: String,
+    user: String,
+    data_length: u64,
+    miners: usize,
+    /// whether the miner holds the order
+    mine: bool,
+    /// whether the miner confirmed the order
+    confirmed: bool,
+}
+
+pub fn orders(settings: &Settings, mine: bool, as_json: bool) -> Result<()> {
+    let sub_client = sub_client(settings)?;
+    let account = AccountId32::from(miner_pair(settings)?.public());
+
+    let rows: Vec<_> = executor::block_on(chain::orders(&sub_client))?.into_iter().enumerate()
+        .map(|(order_id, o)| {
+            let own = o.orders.iter().find(|m| m.miner == account);
+            OrderRow {
+                order_id: order_id as u64,
+                hash: String::from_utf8_lossy(&o.key).into_owned(),
+                user: o.user.to_ss58check(),
+                data_length: o.data_length,
+                miners: o.orders.len(),
+                mine: own.is_some(),
+                confirmed: own.map(|m| m.url.is_some()).unwrap_or(false),
+            }
+        })
+        .filter(|row| !mine || row.mine)
+        .collect();
+    if as_json {
+        return print_json(&rows);
+    }
+
+    println!("{} {}", Paint::blue("Orders:").bold(), rows.len());
+    for row in rows.iter() {
+        let state = match (row.mine, row.confirmed) {
+            (true, true) => Paint::green("confirmed"),
+            (true, false) => Paint::yellow("to confirm"),
+            (false, _) => Paint::new(""),
+        };
+        println!("{:>6} {} {:>12} bytes {} miners {} {}", row.order_id, row.hash, row.data_length, row.miners, row.user, state);
+    }
+    Ok(())
+}
+
+/// Outcome of a queued extrinsic.
+#[derive(Serialize, Debug)]
+struct Submitted {
+    action: &'static str,
+    order_id: u64,
+    extrinsic: Extrinsic,
+}
+
+/// Queue `call` behind the waiting extrinsics, the serve is the only one
+/// submitting the queue so the nonces of the miner account stay in order.
+fn submit(settings: &Settings, call: Call) -> Result<Extrinsic> {
+    let db = kv_database(settings)?.client()?;
+    let id = extrinsic::queue(&db, call)?;
+    extrinsic::list(&db).into_iter().find(|e| e.id == id)
+        .ok_or_else(|| MinerError::msg(format!("extrinsic {} left the queue", id)))
+}
+
+fn print_submitted(submitted: &Submitted, as_json: bool) -> Result<()> {
+    if as_json {
+        return print_json(submitted);
+    }
+    let extrinsic = &submitted.extrinsic;
+    let state = match extrinsic.state {
+        extrinsic::State::Ready | extrinsic::State::InBlock | extrinsic::State::Finalized => Paint::green(submitted.action),
+        extrinsic::State::Queued => Paint::yellow("Queued"),
+        extrinsic::State::Dropped => Paint::red("Dropped"),
+        extrinsic::State::Failed => Paint::red("Failed"),
+    };
+    println!("{} order {} as extrinsic {} {}", state.bold(), submitted.order_id, extrinsic.id, extrinsic.hash.clone().unwrap_or_default());
+    if !extrinsic.last_error.is_empty() {
+        println!("{} {}", Paint::red("Error:").bold(), extrinsic.last_error);
+    }
+    Ok(())
+}
+
+pub fn confirm(settings: &Settings, order_id: u64, as_json: bool) -> Result<()> {
+    let sub_client = sub_client(settings)?;
+    let order = executor::block_on(chain::orders(&sub_client))?.into_iter().nth(order_id as usize)
+        .ok_or_else(|| MinerError::msg(format!("order {} is not on chain", order_id)))?;
+
+    // keep the background confirmation from queueing it again
+    let confirmation = Confirmation {
+        order_id,
+        hash: String::from_utf8_lossy(&order.key).into_owned(),
+        address: order.user.to_ss58check(),
+        state: State::Submitted,
+        extrinsic: None,
+        attempts: 0,
+        last_error: String::new(),
+        updated: Utc::now().timestamp(),
+    };
+    confirm::put(&kv_database(settings)?.client()?, &confirmation)?;
+
+    let extrinsic = submit(settings, Call::ConfirmOrder { order_id, url: settings.miner.url.clone() })?;
+    print_submitted(&Submitted { action: "Confirmed", order_id, extrinsic }, as_json)
+}
+
+pub fn delete(settings: &Settings, order_id: u64, as_json: bool) -> Result<()> {
+    let extrinsic = submit(settings, Call::DeleteOrder { order_id })?;
+    print_submitted(&Submitted { action: "Deleted", order_id, extrinsic }, as_json)
+}
+
+/// The extrinsic queue and the count of extrinsics in every state.
+#[derive(Serialize, Debug)]
+struct Queue {
+    summary: extrinsic::Summary,
+    extrinsics: Vec<Extrinsic>,
+}
+
+pub fn queue(settings: &Settings, as_json: bool) -> Result<()> {
+    let extrinsics = extrinsic::list(&kv_database(settings)?.client()?);
+    let queue = Queue { summary: extrinsic::summary(&extrinsics), extrinsics };
+    if as_json {
+        return print_json(&queue);
+    }
+
+    let summary = &queue.summary;
+    println!("{} {} queued, {} ready, {} in block, {} finalized, {} dropped, {} failed", Paint::blue("Extrinsics:").bold(),
+             summary.queued, summary.ready, summary.in_block, summary.finalized, summary.dropped, summary.failed);
+    for e in queue.extrinsics.iter() {
+        let call = match &e.call {
+            Call::RegisterMiner => "register miner".to_owned(),
+            Call::UpdateMiner => "update miner".to_owned(),
+            Call::ConfirmOrder { order_id, .. } => format!("confirm order {}", order_id),
+            Call::DeleteOrder { order_id } => format!("delete order {}", order_id),
+        };
+        let nonce = e.nonce.map(|n| n.to_string()).unwrap_or_default();
+        println!("{:>6} {:<20} {:<10} nonce {:>6} attempts {} {}", e.id, call, format!("{:?}", e.state), nonce, e.attempts, e.last_error);
+    }
+    Ok(())
+}
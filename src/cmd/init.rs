@@ -15,16 +15,24 @@ region = "the_regin_of_miner"
 url = "http://localhost"
 capacity = 1024000000
 unit_price = 100
+# most days an order may be stored or extended by at once
+max_days = 3650
 public_key = "0a0cbbd30b660317c8a1e1ce3294e8e2791f96dff892f4f9642b2d2bc9c4037f"
 secret_seed = "50ba84b3a1a17c3295621d20568f26c8b8993915156d0afda71656e1b7a01013"
 income_address = "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY"
 
 [chain]
 url = "ws://localhost:9944"
+# nodes tried in order when url cannot be reached
+fallback_urls = []
+# decimals and symbol of the chain balance, prices are shown in them
+decimals = 14
+symbol = "POST"
 
 [data]
 db = "db"
 keystore = "keystore"
+uploads = "uploads"
 
 [search]
 url = "https://www.ipse.io/v3/machine/ipse/"
@@ -35,6 +43,28 @@ local = true
 
 [serve]
 secret_key = "%SECRET_KEY%"
+# bearer token of /admin/v0, derived from secret_key when empty, see `miner admin-token`
+admin_key = ""
+
+# token buckets per remote ip and per address, `capacity` requests at once
+# refilled by `per_minute`, a capacity of 0 disables the limit
+[serve.rate_limit]
+persist = false
+upload = { capacity = 10, per_minute = 10 }
+write = { capacity = 60, per_minute = 60 }
+challenge = { capacity = 10, per_minute = 10 }
+read = { capacity = 600, per_minute = 600 }
+
+# storage limit of every address, 0 means unlimited
+[quota]
+max_bytes = 0
+max_objects = 0
+
+# signed json posts of order events, failed deliveries are retried with backoff
+[webhooks]
+urls = []
+expiring_hours = 24
+max_attempts = 10
 "#;
 
 
@@ -74,6 +104,7 @@ pub fn init(name: &str, force: bool) -> Result<()> {
     create_file(&path.join("config.toml"), &config)?;
     create_dir_all(path.join("db"))?;
     create_dir_all(path.join("keystore"))?;
+    create_dir_all(path.join("uploads"))?;
 
     Ok(())
 }
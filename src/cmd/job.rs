@@ -1,28 +1,17 @@
 /// Scheduling tasks  for miner
 use job_scheduler::{JobScheduler, Job};
 
-use crate::settings::{Settings, kv_database, ipfs_client};
-use kvdb::KeyValueDB;
-use chrono::{Local};
+use crate::settings::{Settings, kv_database, ipfs_client, miner_pair, uploads};
 use std::str;
 use futures::executor;
 use std::time::Duration;
 use log::{self, LevelFilter};
 
 use crate::error::Result;
+use crate::lifecycle;
+use crate::webhook::{self, Event};
 
 
-pub fn delete_by_hash(settings: &Settings, hash: &[u8]) {
-    let kv_client = kv_database(settings).unwrap().client().unwrap();
-    let ipfs_client = ipfs_client(settings).unwrap();
-    ipfs_client.delete(str::from_utf8(hash).unwrap());
-
-
-    // let ic = ipfs_client(settings).unwrap();
-    // executor::block_on(ic.files_rm(str::from_utf8(&path.unwrap().unwrap()).unwrap(), true));
-    ()
-}
-
 /// update miner info(capacity)
 // pub fn update_miner_info(settings: &Settings) {
 //
@@ -31,15 +20,42 @@ pub fn delete_by_hash(settings: &Settings, hash: &[u8]) {
 
 pub fn rm_expired_data(settings: &Settings) {
     let kv_client = kv_database(settings).unwrap().client().unwrap();
+    let ipfs_client = ipfs_client(settings).unwrap();
 
-    let select_key = Local::now().format("%Y%m%d%H%M%S").to_string();
-
-    kv_client.iter(1).filter(move |(i, x)|
-        x.starts_with(select_key.as_ref())
-    ).for_each(|(j, y)| delete_by_hash(settings, y.as_ref()));
+    // the on-chain deletions are queued for the serve to submit
+    let expired = lifecycle::delete_expired(&kv_client, &ipfs_client);
+    for e in expired.errors.iter() {
+        log::error!("delete expired data error: {}", e);
+    }
+    for deleted in expired.deleted.iter() {
+        let data = serde_json::to_value(deleted).unwrap_or_default();
+        webhook::emit_logged(&kv_client, settings, Event::Expired, &deleted.address, &deleted.hash, data);
+    }
+    if let Err(e) = webhook::notify_expiring(&kv_client, settings) {
+        log::error!("notify expiring orders error: {}", e);
+    }
     ()
 }
 
+/// send the queued webhooks
+pub fn send_webhooks(settings: &Settings) {
+    let delivered = kv_database(settings)
+        .and_then(|kv_database| webhook::deliver(&kv_database.client()?, &settings.webhooks, &miner_pair(settings)?));
+    if let Err(e) = delivered {
+        log::error!("send webhooks error: {}", e);
+    }
+}
+
+
+/// remove resumable uploads that were abandoned
+pub fn rm_stale_uploads(settings: &Settings) {
+    match uploads(settings).and_then(|uploads| uploads.remove_stale()) {
+        Ok(0) => {}
+        Ok(removed) => log::info!("removed {} stale uploads", removed),
+        Err(e) => log::error!("remove stale uploads error: {}", e),
+    }
+}
+
 
 pub fn job(settings: &Settings) ->Result<()> {
     let mut sched = JobScheduler::new();
@@ -51,6 +67,8 @@ pub fn job(settings: &Settings) ->Result<()> {
         println!("start rm expired data file");
         rm_expired_data(settings);
         println!("end rm expired data file");
+        rm_stale_uploads(settings);
+        send_webhooks(settings);
     }));
 
     loop {
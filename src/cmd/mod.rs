@@ -2,8 +2,16 @@ mod serve;
 mod init;
 mod job;
 mod generate;
+mod verify;
+mod reconcile;
+mod register;
+mod chain;
 
 pub use self::serve::serve;
 pub use self::init::init;
 pub use self::generate::generate;
-pub use self::job::job;
\ No newline at end of file
+pub use self::job::job;
+pub use self::verify::verify_proof;
+pub use self::reconcile::reconcile;
+pub use self::register::register;
+pub use self::chain::{info as chain_info, orders as chain_orders, confirm as chain_confirm, delete as chain_delete, queue as chain_queue};
\ No newline at end of file
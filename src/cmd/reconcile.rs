@@ -0,0 +1,71 @@
+use yansi::Paint;
+
+use crate::error::Result;
+use crate::reconcile::{self, Entry, Fix, Report};
+use crate::settings::{Settings, kv_database, ipfs_client, sub_client, miner_pair};
+
+
+fn print_entries(title: &str, entries: &[Entry]) {
+    if entries.is_empty() {
+        return;
+    }
+    println!("{} {}", Paint::yellow(title).bold(), entries.len());
+    for entry in entries {
+        match entry.order_id {
+            Some(order_id) => println!("    {} of {} (order {})", entry.hash, entry.address, order_id),
+            None => println!("    {} of {}", entry.hash, entry.address),
+        }
+    }
+}
+
+fn print_report(report: &Report) {
+    println!("{} {}", Paint::blue("Miner").bold(), report.miner);
+    if !report.registered {
+        println!("{}", Paint::red("not registered on chain").bold());
+    }
+    for diff in report.miner_diffs.iter() {
+        println!("{} {} is {} on chain, {} in the settings, run `miner register`",
+                 Paint::yellow("Registration differs:").bold(), diff.field, diff.chain, diff.settings);
+    }
+    println!("{} chain orders, {} local orders", report.chain_orders, report.local_orders);
+
+    print_entries("Missing on the node:", &report.missing);
+    print_entries("Local orders not pinned:", &report.unpinned);
+    print_entries("Local orders unknown to the chain:", &report.orphaned);
+    print_entries("Local orders without their chain id:", &report.unlinked);
+    if !report.stray_pins.is_empty() {
+        println!("{} {}", Paint::yellow("Pins without order:").bold(), report.stray_pins.len());
+        for hash in report.stray_pins.iter() {
+            println!("    {}", hash);
+        }
+    }
+
+    for fixed in report.fixed.iter() {
+        println!("{} {}", Paint::green("Fixed:").bold(), fixed);
+    }
+    for e in report.errors.iter() {
+        println!("{} {}", Paint::red("Error:").bold(), e);
+    }
+    if report.is_clean() {
+        println!("{}", Paint::green("Database, node and chain agree").bold());
+    }
+}
+
+/// Compare the local orders with the chain and optionally fix the drifts.
+pub fn reconcile(settings: &Settings, fix: Fix, as_json: bool) -> Result<()> {
+    let report = reconcile::reconcile(
+        &kv_database(settings)?.client()?,
+        &ipfs_client(settings)?,
+        &miner_pair(settings)?,
+        &sub_client(settings)?,
+        settings,
+        fix,
+    )?;
+
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use futures::executor;
+use sp_core::Pair as PairT;
+use sp_core::crypto::AccountId32;
+use yansi::Paint;
+
+use crate::chain::{self, Registration};
+use crate::error::Result;
+use crate::extrinsic;
+use crate::settings::{Settings, kv_database, sub_client, miner_pair};
+
+
+/// Queue the call bringing the on-chain miner profile in line with the config,
+/// only showing the differences on a `dry_run`. The serve submits the queue.
+pub fn register(settings: &Settings, dry_run: bool, as_json: bool) -> Result<()> {
+    let sub_client = sub_client(settings)?;
+    let account = AccountId32::from(miner_pair(settings)?.public());
+    let mut registration = executor::block_on(chain::registration(settings, account, &sub_client))?;
+    if !dry_run {
+        if let Some(call) = registration.call(&sub_client)? {
+            registration.queued(extrinsic::queue(&kv_database(settings)?.client()?, call)?);
+        }
+    }
+
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&registration)?);
+        return Ok(());
+    }
+    match registration {
+        Registration::Unchanged => println!("{}", Paint::green("The on-chain profile matches the config").bold()),
+        Registration::Register { extrinsic: None } => println!("{}", Paint::yellow("The miner is not registered yet").bold()),
+        Registration::Register { extrinsic: Some(id) } => println!("{} as extrinsic {}", Paint::green("Registration queued").bold(), id),
+        Registration::Update { diffs, extrinsic } => {
+            for diff in diffs.iter() {
+                println!("{} {} -> {}", Paint::yellow(diff.field).bold(), diff.chain, diff.settings);
+            }
+            match extrinsic {
+                Some(id) => println!("{} as extrinsic {}", Paint::green("Update queued").bold(), id),
+                None => println!("{}", Paint::yellow("Nothing queued, run without --dry-run to update").bold()),
+            }
+        }
+    }
+    Ok(())
+}
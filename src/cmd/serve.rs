@@ -1,10 +1,11 @@
+mod admin;
+
 mod routes {
-    use tempdir::TempDir;
     use rocket::Data;
     use hex_literal::hex;
     use rocket_contrib::json::{Json, JsonValue};
-    use codec::{Encode, Decode};
-    use chrono::{Local, Duration};
+    use codec::Encode;
+    use chrono::Local;
     use ubyte::ToByteUnit;
     use sp_core::crypto::Ss58Codec;
     use hex as hhex;
@@ -13,58 +14,513 @@ mod routes {
 
 
     use rocket::State;
-
-    use crate::cmd::serve::{ClientConfig, DataInfo, DataAddInfo, MinerRequest};
+    use rocket::request::LenientForm;
+
+    use std::io::{self, Read};
+    use std::fs::File;
+    use kvdb_rocksdb::Database;
+
+    use serde::de::DeserializeOwned;
+
+    use crate::auth;
+    use crate::lifecycle;
+    use crate::pricing::{self, Quote};
+    use crate::ratelimit::Limited;
+    use crate::webhook::{self, Event};
+    use crate::confirm;
+    use crate::extrinsic;
+    use crate::cmd::serve::{ClientConfig, ContentLength, SignedRequest, DataHeaders, DataResponse, DataInfo, DataAddInfo, MinerRequest, OrderQuery, ChallengeQuery, QuoteQuery, ExtendInfo, UploadInfo, UploadOffset, UploadProgress, FieldDiff};
+    use crate::storage::upload::Session;
+    use crate::constants::{ORDER_COL, HASH_COL, UNPAID_COL, INFO_COL, CHAIN_COL, MERKLE_COL};
     use crate::util::id::PasteID;
-    use crate::error::{Result, MinerError};
+    use crate::util::data::{self, HashReader};
+    use crate::util::merkle::{self, Leaves, MerkleReader};
+    use crate::proof::{self, Proof};
+    use crate::error::{Result, MinerError, MinerErrorKind};
     use crate::storage::ipfs::client::Stat;
+    use crate::storage::kv::order::{self, OrderInfo, UploadedFile};
+    use rocket::http::{Status, ContentType};
+    use rocket::response::status;
+    use crate::storage::ipfs::model::DiskInfo;
     use sp_core::Pair;
     use std::collections::HashMap;
 
+    /// largest json body accepted on signed routes
+    const JSON_LIMIT: u64 = 1024 * 1024;
+
+    /// bytes `address` may still upload, limited by the miner capacity and the address quota,
+    /// the open upload sessions but `session` count as stored with their full length
+    fn upload_limit(client_config: &ClientConfig, kv_client: &Database, address: &str, session: Option<&str>) -> Result<u64> {
+        let settings = &client_config.settings;
+        let uploads = &client_config.uploads;
+
+        let used = order::usage(kv_client, b"");
+        let reserved = uploads.reserved(None, session)?;
+        let available = settings.miner.capacity.saturating_sub(used.bytes + reserved.bytes);
+        if available == 0 {
+            return Err(MinerError::new(MinerErrorKind::CapacityExceeded(0)));
+        }
+
+        let limit = settings.quota.limit(address);
+        let mine = order::usage(kv_client, address.as_bytes());
+        let reserved = uploads.reserved(Some(address), session)?;
+        let (bytes, objects) = (mine.bytes + reserved.bytes, mine.objects + reserved.sessions);
+        if limit.max_objects > 0 && objects >= limit.max_objects {
+            return Err(MinerError::new(MinerErrorKind::QuotaExceeded(format!("{} of {} objects stored or uploading", objects, limit.max_objects))));
+        }
+        if limit.max_bytes > 0 {
+            if bytes >= limit.max_bytes {
+                return Err(MinerError::new(MinerErrorKind::QuotaExceeded(format!("{} of {} bytes stored or uploading", bytes, limit.max_bytes))));
+            }
+            return Ok(available.min(limit.max_bytes - bytes));
+        }
+        Ok(available)
+    }
+
+    /// the limit `order::store` checks again under its counters lock,
+    /// the open upload sessions but `session` hold back their full length
+    fn store_limit(client_config: &ClientConfig, address: &str, session: Option<&str>) -> Result<order::StoreLimit> {
+        let settings = &client_config.settings;
+        let uploads = &client_config.uploads;
+
+        let reserved = uploads.reserved(None, session)?;
+        let limit = settings.quota.limit(address);
+        let mine = uploads.reserved(Some(address), session)?;
+        Ok(order::StoreLimit {
+            capacity: settings.miner.capacity.saturating_sub(reserved.bytes),
+            max_bytes: Some(limit.max_bytes).filter(|&max| max > 0).map(|max| max.saturating_sub(mine.bytes)),
+            max_objects: Some(limit.max_objects).filter(|&max| max > 0).map(|max| max.saturating_sub(mine.sessions)),
+        })
+    }
+
+    /// unpin data that was added but is not stored as an order,
+    /// it is only kept when another order holds the same hash
+    fn discard(client_config: &ClientConfig, kv_client: &Database, hash: &str) -> Result<()> {
+        if kv_client.get(HASH_COL, hash.as_bytes())?.is_none() {
+            client_config.ipfs_client.delete(hash)?;
+        }
+        Ok(())
+    }
+
+    /// days an order is stored or extended by must be positive and at most `max_days`
+    fn check_days(client_config: &ClientConfig, days: u64) -> Result<()> {
+        let max_days = client_config.settings.miner.max_days;
+        if days == 0 || days > max_days {
+            return Err(MinerError::invalid(format!("days must be 1 to {}", max_days)));
+        }
+        Ok(())
+    }
+
+    /// read a json body whose hash was signed by the caller
+    fn signed_json<T: DeserializeOwned>(signed: &SignedRequest, data: Data) -> Result<T> {
+        let mut reader = HashReader::new(data.open().take(JSON_LIMIT));
+        let mut body = vec![];
+        reader.read_to_end(&mut body)?;
+
+        if reader.summary().digest() != signed.0.body_hash {
+            return Err(auth::unauthorized("request body does not match the signed body hash"));
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
     #[post("/order/<address>", data = "<data>")]
-    pub(crate) fn create_order(client_config: State<'_, ClientConfig>, address: String, data: Data) -> Result<JsonValue> {
-        let tmp_dir = TempDir::new(address.as_str())?;
-        let filename = PasteID::rand().to_string();
-        let file_path = tmp_dir.path().join(format!("{}", filename));
+    pub(crate) fn create_order(client_config: State<'_, ClientConfig>, address: String, signed: SignedRequest, length: ContentLength, content_type: Option<&ContentType>, data: Data) -> Result<JsonValue> {
+        let kv_client = &*client_config.db;
 
-        data.stream_to_file(file_path.clone())
-            .map(|n| format!("Wrote {} bytes to {:?}", n, file_path.to_str()))?;
+        let limit = upload_limit(&client_config, kv_client, &address, None)?;
+        if let ContentLength(Some(length)) = length {
+            if length > limit {
+                return Err(MinerError::new(MinerErrorKind::CapacityExceeded(limit)));
+            }
+        }
 
         let client = &client_config.ipfs_client;
 
-        let resp = client.add(file_path.to_str()?)?;
+        // pipe the request body straight into ipfs. a body growing past the limit, or not matching
+        // the signed hash at its end, fails the stream so ipfs never completes the add.
+        // a multipart body is stored as one directory holding all of its files
+        let reader = HashReader::new(data.open()).limit(limit).expect(&signed.0.body_hash);
+        let checked = reader.summary();
+        let added = match content_type {
+            Some(content_type) if content_type.is_form_data() => {
+                client.add_multipart(&content_type.to_string(), reader).map(|(stat, added)| {
+                    let files: Vec<_> = added.into_iter().map(|a| UploadedFile {
+                        size: a.size.parse().unwrap_or_default(),
+                        name: a.name,
+                        hash: a.hash,
+                    }).collect();
+                    (stat, files, None)
+                })
+            }
+            _ => {
+                let filename = PasteID::new(16).to_string();
+                let reader = MerkleReader::new(reader);
+                let leaves = reader.handle();
+                client.add_stream(filename.as_str(), reader).map(|stat| (stat, vec![], Some(leaves.leaves())))
+            }
+        };
 
+        if checked.exceeded() {
+            return Err(MinerError::new(MinerErrorKind::CapacityExceeded(limit)));
+        }
+        if checked.mismatched() {
+            return Err(auth::unauthorized("uploaded data does not match the signed body hash"));
+        }
+        let (resp, files, leaves) = added?;
 
-        let kv_client = &client_config.kv_database.client()?;
+        store_order(&client_config, kv_client, &address, None, resp, files, leaves)
+    }
 
-        let mut batch = kv_client.transaction();
+    /// record an upload added to ipfs as an order of `address`,
+    /// data passing the limit since it was checked is unpinned again
+    fn store_order(client_config: &ClientConfig, kv_client: &Database, address: &str, session: Option<&str>, resp: Stat, files: Vec<UploadedFile>, leaves: Option<Leaves>) -> Result<JsonValue> {
+        let limit = store_limit(client_config, address, session)?;
+        let exceeded = match order::store(kv_client, address, &resp, &files, leaves.as_ref(), &limit)? {
+            order::Stored::Stored => None,
+            order::Stored::CapacityExceeded(available) => Some(MinerErrorKind::CapacityExceeded(available)),
+            order::Stored::QuotaExceeded(msg) => Some(MinerErrorKind::QuotaExceeded(msg)),
+        };
+        if let Some(kind) = exceeded {
+            discard(client_config, kv_client, &resp.hash)?;
+            return Err(MinerError::new(kind));
+        }
+
+        let mut value = serde_json::to_value(&resp)?;
+        if !files.is_empty() {
+            value["files"] = serde_json::to_value(&files)?;
+        }
+        if let Some(leaves) = leaves {
+            value["merkle_root"] = serde_json::Value::String(hhex::encode(leaves.root()));
+        }
+
+        webhook::emit_logged(kv_client, &client_config.settings, Event::Uploaded, address, &resp.hash, value.clone());
+        Ok(JsonValue(value))
+    }
 
-        let ipfs_response_encode = resp.encode();
+    /// start a resumable upload of `length` bytes with the blake2b-256 `digest`
+    #[post("/upload/<address>", data = "<data>")]
+    pub(crate) fn create_upload(client_config: State<'_, ClientConfig>, address: String, signed: SignedRequest, data: Data) -> Result<JsonValue> {
+        let data: UploadInfo = signed_json(&signed, data)?;
+
+        let kv_client = &*client_config.db;
+        let limit = upload_limit(&client_config, kv_client, &address, None)?;
+        if data.length > limit {
+            return Err(MinerError::new(MinerErrorKind::CapacityExceeded(limit)));
+        }
+
+        let session = client_config.uploads.create(&address, data.length, &data.digest)?;
+        Ok(json!({
+            "id": session.id,
+            "offset": 0,
+            "length": session.length,
+        }))
+    }
 
-        // address-time: ipfs_response{}
-        batch.put(0, [address.clone(), resp.hash.clone(), Local::now().format("%Y%m%d%H%M%S").to_string()].concat().as_ref(), ipfs_response_encode.as_ref());
+    fn upload_session(client_config: &ClientConfig, address: &str, id: &str) -> Result<Session> {
+        client_config.uploads.get(id)?
+            .filter(|session| session.address == address)
+            .ok_or_else(|| MinerError::new(MinerErrorKind::FileNotFound))
+    }
 
-        //save hash data path
-        // 插入数据，如果是 1 表示新插入数据
-        batch.put(1, resp.hash.clone().as_str().as_ref(), b"1");
+    /// append the chunk starting at the `Upload-Offset` header
+    #[patch("/upload/<address>/<id>", data = "<data>")]
+    pub(crate) fn append_upload(client_config: State<'_, ClientConfig>, address: String, id: String, signed: SignedRequest, offset: UploadOffset, data: Data) -> Result<JsonValue> {
+        let mut session = upload_session(&client_config, &address, &id)?;
+
+        let offset = client_config.uploads.append(&mut session, offset.0, data.open(), &signed.0.body_hash)?;
+        Ok(json!({
+            "id": session.id,
+            "offset": offset,
+            "length": session.length,
+        }))
+    }
 
+    /// progress of an upload in the `Upload-Offset` and `Upload-Length` headers
+    #[head("/upload/<address>/<id>")]
+    pub(crate) fn upload_progress(client_config: State<'_, ClientConfig>, address: String, id: String) -> Result<UploadProgress> {
+        let session = upload_session(&client_config, &address, &id)?;
 
-        // add data hash pay flag
-        batch.put(2, resp.hash.clone().as_str().as_ref(), b"");
+        Ok(UploadProgress {
+            offset: client_config.uploads.offset(&session)?,
+            length: session.length,
+        })
+    }
 
-        kv_client.write(batch)?;
+    /// add a complete upload to ipfs and record it like a single upload
+    #[post("/upload/<address>/<id>/finalize")]
+    pub(crate) fn finalize_upload(client_config: State<'_, ClientConfig>, address: String, id: String, _signed: SignedRequest) -> Result<JsonValue> {
+        let session = upload_session(&client_config, &address, &id)?;
+        let _lock = client_config.uploads.lock(&session.id)?;
+
+        let offset = client_config.uploads.offset(&session)?;
+        if offset != session.length {
+            return Err(MinerError::new(MinerErrorKind::Conflict(format!("upload is incomplete, {} of {} bytes", offset, session.length))));
+        }
+
+        let kv_client = &*client_config.db;
+        let limit = upload_limit(&client_config, kv_client, &address, Some(&session.id))?;
+        if session.length > limit {
+            return Err(MinerError::new(MinerErrorKind::CapacityExceeded(limit)));
+        }
+
+        // the assembled data is checked before it reaches ipfs
+        let path = client_config.uploads.data_path(&session.id);
+        let mut reader = HashReader::new(File::open(&path)?);
+        io::copy(&mut reader, &mut io::sink())?;
+        if reader.summary().digest() != session.digest {
+            return Err(auth::unauthorized("uploaded data does not match the signed digest"));
+        }
+        let resp = client_config.ipfs_client.add(path.to_str()?)?;
+
+        let leaves = merkle::leaves(File::open(&path)?)?;
+        let stored = store_order(&client_config, kv_client, &address, Some(&session.id), resp, vec![], Some(leaves))?;
+        client_config.uploads.remove(&session.id)?;
+        Ok(stored)
+    }
+
+    /// requests stopped by the rate limiter end here, see `ratelimit`
+    #[get("/rate-limited")]
+    pub(crate) fn rate_limited(limited: Limited) -> Result<JsonValue> {
+        Err(MinerError::new(MinerErrorKind::RateLimited(limited.0.max(1))))
+    }
+
+    /// health of the miner and of the services it depends on,
+    /// answered with 503 when one of them is down
+    #[get("/status")]
+    pub(crate) fn status(client_config: State<'_, ClientConfig>) -> status::Custom<JsonValue> {
+        let (ipfs_ok, ipfs) = match client_config.ipfs_client.repo_stat() {
+            Ok(repo) => (true, json!({"ok": true, "version": repo.version, "repo_size": repo.repo_size, "storage_max": repo.storage_max})),
+            Err(e) => (false, json!({"ok": false, "error": e.to_string()})),
+        };
+
+        let chain = client_config.chain.status();
+
+        let capacity = client_config.settings.miner.capacity;
+        let kv_client = &*client_config.db;
+        let (db_ok, db) = match kv_client.get(ORDER_COL, b"") {
+            Ok(_) => (true, json!({"ok": true})),
+            Err(e) => (false, json!({"ok": false, "error": e.to_string()})),
+        };
+        let used = order::usage(kv_client, b"");
+        let extrinsics = extrinsic::summary(&extrinsic::list(kv_client));
+
+        let disk = match DiskInfo::of(&client_config.settings.data.db) {
+            Ok(disk) => json!(disk),
+            Err(e) => json!({"error": e.to_string()}),
+        };
+
+        // without the chain the miner still stores and serves data
+        let healthy = ipfs_ok && db_ok;
+        let degraded = !chain.connected;
+        let uptime = Local::now().signed_duration_since(client_config.started).num_seconds();
+        let body = json!({
+            "status": if !healthy { "down" } else if degraded { "degraded" } else { "ok" },
+            "version": env!("CARGO_PKG_VERSION"),
+            "started": client_config.started.format("%Y%m%d%H%M%S").to_string(),
+            "uptime": uptime,
+            "ipfs": ipfs,
+            "chain": {
+                "ok": chain.connected,
+                "url": chain.url,
+                "best_block": chain.best_block,
+                "attempts": chain.attempts,
+                "error": chain.last_error,
+                "since": chain.since,
+                "extrinsics": extrinsics,
+            },
+            "db": db,
+            "disk": disk,
+            "capacity": {
+                "advertised": capacity,
+                "used": used.bytes,
+                "objects": used.objects,
+            },
+        });
+
+        status::Custom(if healthy { Status::Ok } else { Status::ServiceUnavailable }, body)
+    }
+
+    /// storage used on the miner against the advertised capacity
+    #[get("/usage")]
+    pub(crate) fn usage(client_config: State<'_, ClientConfig>) -> Result<JsonValue> {
+        let kv_client = &*client_config.db;
+        let capacity = client_config.settings.miner.capacity;
+
+        let used = order::usage(kv_client, b"");
+        let reserved = client_config.uploads.reserved(None, None)?;
+        let repo = client_config.ipfs_client.repo_stat().ok();
+
+        Ok(json!({
+            "capacity": capacity,
+            "used": used.bytes,
+            "reserved": reserved,
+            "available": capacity.saturating_sub(used.bytes + reserved.bytes),
+            "objects": used.objects,
+            "repo": repo,
+        }))
+    }
+
+    /// storage used by one address against its quota
+    #[get("/usage/<address>")]
+    pub(crate) fn address_usage(client_config: State<'_, ClientConfig>, address: String) -> Result<JsonValue> {
+        let kv_client = &*client_config.db;
+
+        let used = order::usage(kv_client, address.as_bytes());
+        let reserved = client_config.uploads.reserved(Some(&address), None)?;
+        let limit = client_config.settings.quota.limit(&address);
+
+        Ok(json!({
+            "address": address,
+            "used": used.bytes,
+            "objects": used.objects,
+            "reserved": reserved,
+            "max_bytes": limit.max_bytes,
+            "max_objects": limit.max_objects,
+        }))
+    }
+
+    /// orders of an address, filtered by category/label and sorted by time or size
+    #[get("/order/<address>?<query..>")]
+    pub(crate) fn list_orders(client_config: State<'_, ClientConfig>, address: String, query: LenientForm<OrderQuery>) -> Result<JsonValue> {
+        let kv_client = &*client_config.db;
+
+        let mut entries = order::list(kv_client, &address)?;
+        if let Some(ref category) = query.category {
+            entries.retain(|e| e.category.as_ref() == Some(category));
+        }
+        if let Some(ref label) = query.label {
+            entries.retain(|e| e.label.as_ref() == Some(label));
+        }
+
+        match query.sort.as_ref().map(String::as_str) {
+            Some("size") => entries.sort_by_key(|e| e.size),
+            None | Some("time") => entries.sort_by(|a, b| a.time.cmp(&b.time)),
+            Some(sort) => return Err(MinerError::invalid(format!("unknown sort `{}`, expected `time` or `size`", sort))),
+        }
+        if query.order.as_ref().map(String::as_str) != Some("asc") {
+            entries.reverse();
+        }
+
+        let total = entries.len();
+        let page = query.page.unwrap_or(1).max(1);
+        let size = query.size.unwrap_or(20).max(1).min(100);
+        let entries: Vec<_> = entries.into_iter().skip((page - 1).saturating_mul(size)).take(size).collect();
+
+        Ok(json!({
+            "address": address,
+            "total": total,
+            "page": page,
+            "size": size,
+            "orders": entries,
+        }))
+    }
+
+    /// everything the miner knows about one order
+    #[get("/order/<address>/<hash>")]
+    pub(crate) fn order_detail(client_config: State<'_, ClientConfig>, address: String, hash: String) -> Result<JsonValue> {
+        let kv_client = &*client_config.db;
+
+        let order = order::find(kv_client, &address, &hash)
+            .ok_or_else(|| MinerError::new(MinerErrorKind::FileNotFound))?;
+        let info = order::get_info(kv_client, &address, &hash)?;
+        let order_id = order::get_order_id(kv_client, &address, &hash)?;
+        let confirmation = match order_id {
+            Some(order_id) => confirm::get(kv_client, order_id)?,
+            None => None,
+        };
 
-        tmp_dir.close()?;
-        Ok(json!(resp))
+        let pinned = match client_config.ipfs_client.pin_ls(&hash) {
+            Ok(pinned) => Some(pinned),
+            Err(e) => {
+                log::warn!("pin state of {} unknown: {}", hash, e);
+                None
+            }
+        };
+
+        Ok(json!({
+            "address": address,
+            "hash": hash,
+            "time": order.time,
+            "stat": order.stat,
+            "expire": info.as_ref().map(|i| i.expire.clone()),
+            "info": info,
+            "paid": !order::is_unpaid(kv_client, &hash)?,
+            "order_id": order_id,
+            "confirmation": confirmation,
+            "files": order::get_files(kv_client, &address, &hash)?,
+            "extensions": order::get_extensions(kv_client, &address, &hash)?,
+            "pinned": pinned,
+        }))
+    }
+
+    /// content of a stored order, ranges are served from the ipfs node directly
+    #[get("/data/<hash>")]
+    pub(crate) fn get_data(client_config: State<'_, ClientConfig>, hash: String, headers: DataHeaders) -> Result<DataResponse> {
+        let kv_client = &*client_config.db;
+
+        // only serve data of live orders, the miner is no public gateway
+        if kv_client.get(HASH_COL, hash.as_bytes())?.is_none() {
+            return Err(MinerError::new(MinerErrorKind::FileNotFound));
+        }
+
+        // content addressed data never changes, its hash is a strong etag
+        let etag = format!("\"{}\"", hash);
+        if headers.matches(&etag) {
+            return Ok(DataResponse {
+                status: Status::NotModified,
+                etag,
+                content_type: ContentType::Binary,
+                content_range: None,
+                body: None,
+            });
+        }
+
+        let client = &client_config.ipfs_client;
+        let size = client.files_stat(&hash)?.size;
+
+        let mut head = vec![];
+        client.cat(&hash, 0, Some(512))?.read_to_end(&mut head)?;
+        let content_type = data::sniff_content_type(&head);
+
+        let range = match headers.range.as_ref().map(|r| data::parse_range(r, size)) {
+            Some(Err(())) => {
+                return Ok(DataResponse {
+                    status: Status::RangeNotSatisfiable,
+                    etag,
+                    content_type,
+                    content_range: Some(format!("bytes */{}", size)),
+                    body: None,
+                });
+            }
+            Some(Ok(range)) => range,
+            None => None,
+        };
+
+        let (status, offset, length, content_range) = match range {
+            Some((start, end)) => (Status::PartialContent, start, end - start + 1, Some(format!("bytes {}-{}/{}", start, end, size))),
+            None => (Status::Ok, 0, size, None),
+        };
+
+        let body = if length > 0 { Some(client.cat(&hash, offset, Some(length))?) } else { None };
+
+        Ok(DataResponse {
+            status,
+            etag,
+            content_type,
+            content_range,
+            body,
+        })
     }
 
     #[post("/order/<address>/<hash>", format = "json", data = "<data>")]
-    pub(crate) fn create_order_info(client_config: State<'_, ClientConfig>, address: String, hash: String, data: Json<DataAddInfo>) -> Result<JsonValue> {
-        let kv_client = &client_config.kv_database.client()?;
+    pub(crate) fn create_order_info(client_config: State<'_, ClientConfig>, address: String, hash: String, signed: SignedRequest, data: Data) -> Result<JsonValue> {
+        let data: DataAddInfo = signed_json(&signed, data)?;
+        check_days(&client_config, data.days)?;
+        let kv_client = &*client_config.db;
         let settings = &client_config.settings;
 
-        let res = kv_client.get_by_prefix(0, [&address, hash.to_owned().as_str()].concat().as_ref())?;
-        let ipfs_write_decode = Stat::decode(&mut &res[..])?;
+        let res = kv_client.get_by_prefix(ORDER_COL, [&address, hash.to_owned().as_str()].concat().as_ref())?;
+        let ipfs_write_decode = Stat::from_record(&res)?;
 
         let pair = &client_config.pair;
 
@@ -106,47 +562,160 @@ mod routes {
             .json(&search_info)
             .send()?;
 
-        let expire_date = Local::now() + Duration::days(data.days as i64);
+        let expire_date = order::add_days(Local::now(), data.days)
+            .ok_or_else(|| MinerError::invalid("days do not fit a date"))?;
 
         // delete data hash pay flag
-        batch.delete(2, ipfs_write_decode.hash.as_str().as_ref());
+        batch.delete(UNPAID_COL, ipfs_write_decode.hash.as_str().as_ref());
+
+        // save order info with the expire time
+        let info = OrderInfo {
+            name: data.name.to_owned(),
+            label: data.label.to_owned(),
+            category: data.category.to_owned(),
+            describe: data.describe.to_owned(),
+            days: data.days,
+            expire: expire_date.format("%Y%m%d%H%M%S").to_string(),
+        };
+        batch.put(INFO_COL, &order::info_key(&search_info.address, &ipfs_write_decode.hash), &info.encode());
 
-        // save expire info
-        batch.put(0, [ipfs_write_decode.hash.as_str(), Local::now().format("%Y%m%d%H%M%S").to_string().as_str()].concat().as_ref(), expire_date.format("%Y%m%d%H%M%S").to_string().as_ref());
+        if let Some(order_id) = data.order_id {
+            batch.put(CHAIN_COL, &order::info_key(&search_info.address, &ipfs_write_decode.hash), &order_id.encode());
+        }
 
 
         kv_client.write(batch)?;
 
+        webhook::emit_logged(kv_client, settings, Event::MetadataAdded, &search_info.address, &search_info.hash, json!({
+            "info": info,
+            "order_id": data.order_id,
+        }).into());
+
         Ok(json!(search_info))
     }
 
 
-    #[delete("/order/<address>/<hash>")]
-    pub(crate) fn delete_order(client_config: State<'_, ClientConfig>, address: String, hash: String) -> Result<JsonValue> {
-        let client = &client_config.ipfs_client;
-        let res = client.delete(hash.to_owned().as_str());
-
-
-        let kv_client = &client_config.kv_database.client()?;
+    /// binding price of storing `size` bytes for `days` days
+    #[get("/quote?<query..>")]
+    pub(crate) fn quote(client_config: State<'_, ClientConfig>, query: LenientForm<QuoteQuery>) -> Result<JsonValue> {
+        check_days(&client_config, query.days)?;
 
-        // let mut res = kv_client.get_by_prefix(0, [address, hash_data.unwrap().as_str()].concat().as_ref()).unwrap().get(0).unwrap();
-        let res = kv_client.get_by_prefix(0, [address.as_str(), hash.to_owned().as_str()].concat().as_ref())?;
+        let quote = Quote::new(&client_config.pair, &client_config.settings.chain, client_config.settings.miner.unit_price, query.size, query.days);
+        Ok(json!(quote))
+    }
 
+    /// add days to the expiry of an order, priced from the miner `unit_price`,
+    /// ranked below `verify_order` which matches the same paths
+    #[post("/order/<address>/<hash>/extend", data = "<data>", rank = 2)]
+    pub(crate) fn extend_order(client_config: State<'_, ClientConfig>, address: String, hash: String, signed: SignedRequest, data: Data) -> Result<JsonValue> {
+        let data: ExtendInfo = signed_json(&signed, data)?;
+        check_days(&client_config, data.days)?;
+
+        let kv_client = &*client_config.db;
+        let order = order::find(kv_client, &address, &hash)
+            .ok_or_else(|| MinerError::new(MinerErrorKind::FileNotFound))?;
+
+        let price = pricing::price(client_config.settings.miner.unit_price, order.stat.st_size as u64, data.days);
+        let (info, extension) = order::extend(kv_client, &address, &hash, data.days, price)?
+            .ok_or_else(|| MinerError::new(MinerErrorKind::Conflict("order info was not added yet".to_owned())))?;
+
+        webhook::emit_logged(kv_client, &client_config.settings, Event::Extended, &address, &hash, serde_json::to_value(&extension)?);
+
+        Ok(json!({
+            "address": address,
+            "hash": hash,
+            "days": extension.days,
+            "price": extension.price.to_string(),
+            "previous_expire": extension.previous_expire,
+            "expire": info.expire,
+            "extensions": order::get_extensions(kv_client, &address, &hash)?,
+        }))
+    }
 
-        let ipfs_write_decode = Stat::decode(&mut &res[..])?;
+    #[delete("/order/<address>/<hash>")]
+    pub(crate) fn delete_order(client_config: State<'_, ClientConfig>, address: String, hash: String, _signed: SignedRequest) -> Result<JsonValue> {
+        let kv_client = &*client_config.db;
+
+        let deleted = lifecycle::delete_order(
+            kv_client,
+            &client_config.ipfs_client,
+            &address,
+            &hash,
+        )?;
+
+        webhook::emit_logged(kv_client, &client_config.settings, Event::Deleted, &address, &hash, serde_json::to_value(&deleted)?);
+        Ok(json!(deleted))
+    }
 
+    /// prove the miner still holds `hash` by answering a challenge seed with
+    /// the picked chunks and their merkle paths, signed by the miner
+    #[get("/challenge/<hash>?<query..>")]
+    pub(crate) fn challenge(client_config: State<'_, ClientConfig>, hash: String, query: LenientForm<ChallengeQuery>) -> Result<JsonValue> {
+        let seed = hhex::decode(query.seed.trim_start_matches("0x"))
+            .map_err(|_| MinerError::invalid("seed is not hex encoded"))?;
+        if seed.is_empty() || seed.len() > 64 {
+            return Err(MinerError::invalid("seed must have 1 to 64 bytes"));
+        }
+        let count = query.count.unwrap_or(proof::DEFAULT_CHALLENGES).min(proof::MAX_CHALLENGES).max(1);
+
+        let kv_client = &*client_config.db;
+        if kv_client.get(HASH_COL, hash.as_bytes())?.is_none() {
+            return Err(MinerError::new(MinerErrorKind::FileNotFound));
+        }
+
+        // contents stored before leaves were recorded are read once to record them,
+        // directories have no content of their own
+        let leaves = match order::get_leaves(kv_client, &hash)? {
+            Some(leaves) => leaves,
+            None => {
+                if client_config.ipfs_client.files_stat(&hash)?.typ == "directory" {
+                    return Err(MinerError::invalid("directory orders can not be challenged"));
+                }
+                let leaves = merkle::leaves(client_config.ipfs_client.cat(&hash, 0, None)?)?;
+                let mut batch = kv_client.transaction();
+                batch.put(MERKLE_COL, hash.as_bytes(), &leaves.encode());
+                kv_client.write(batch)?;
+                leaves
+            }
+        };
 
-        Ok(json!(ipfs_write_decode))
+        let proof = Proof::prove(&client_config.ipfs_client, &client_config.pair, &hash, &leaves, &seed, count)?;
+        Ok(json!(proof))
     }
 
+    /// compare the claimed data info with what ipfs holds for the order right now
     #[post("/order/verify/<address>/<hash>", format = "application/json", data = "<data>")]
     pub(crate) fn verify_order(client_config: State<'_, ClientConfig>, address: String, hash: String, data: Json<DataInfo>) -> Result<JsonValue> {
-        let kv_client = &client_config.kv_database.client()?;
-
-        let res = kv_client.get_by_prefix(0, [address.as_str(), hash.as_str()].concat().as_ref())?;
-        let res_data = DataInfo::decode(&mut &res[..])?;
+        let kv_client = &*client_config.db;
+        let stored = order::find(kv_client, &address, &hash)
+            .ok_or_else(|| MinerError::new(MinerErrorKind::FileNotFound))?;
+        let info = order::get_info(kv_client, &address, &hash)?;
+
+        let object = client_config.ipfs_client.object_stat(&hash)?;
+        let files = client_config.ipfs_client.files_stat(&hash)?;
+
+        // the size recorded at upload time is the file size only for single file orders
+        let stored_size = if stored.stat.st_mode & 0o170000 == 0o100000 {
+            Some(stored.stat.st_size.to_string())
+        } else {
+            None
+        };
 
-        Ok(json!({"equal":data.0 == res_data}))
+        let data = data.into_inner();
+        let fields = vec![
+            FieldDiff::new("address", Some(data.address), Some(stored.address), None),
+            FieldDiff::new("name", Some(data.name), info.map(|info| info.name), None),
+            FieldDiff::new("hash", Some(data.hash), Some(stored.hash), Some(files.hash)),
+            FieldDiff::new("size", Some(data.size), stored_size, Some(files.size.to_string())),
+            FieldDiff::new("cumulative_size", Some(data.cumulative_size), None, Some(files.cumulative_size.to_string())),
+            FieldDiff::new("blocks", Some(data.blocks), None, Some(files.blocks.to_string())),
+            FieldDiff::new("links", data.links, None, Some(object.num_links.to_string())),
+        ];
+
+        Ok(json!({
+            "equal": fields.iter().all(|field| field.equal),
+            "fields": fields,
+        }))
     }
 }
 
@@ -155,41 +724,274 @@ use rocket_cors;
 use rocket_contrib::json::JsonValue;
 use rocket_cors::Cors;
 use rocket::config::{Config, Environment};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, content, status, Responder, Response};
+use rocket::fairing::AdHoc;
+use rocket::http::{Status, ContentType};
+use rocket::{Outcome, State};
 
 use futures::executor;
+use chrono::{DateTime, Local};
+use std::thread;
+use std::time::Duration as StdDuration;
+use std::sync::Arc;
 use std::io::{self, Read};
 use kvdb_rocksdb::{DatabaseConfig, Database};
-use substrate_subxt::Client;
 use serde::{Deserialize, Serialize};
 use codec::{Encode, Decode};
 use sp_core::{sr25519::Pair, Pair as PairT};
+use sp_core::crypto::AccountId32;
 use log;
 
 
-use crate::settings::{Settings, sub_client, kv_database, ipfs_client};
-use crate::storage::kv::rocksdb::KVDatabase;
-use crate::chain::IpseRuntime;
-use crate::chain::register_miner;
-use crate::error::Result;
+use crate::settings::{Settings, kv_database, ipfs_client, uploads, miner_pair};
+use crate::storage::kv::order;
+use crate::chain::{registration, Registration};
+use crate::error::{Result, MinerError, MinerErrorKind, GuardError, RecentErrors, RequestId, REQUEST_ID_HEADER, error_body};
 use crate::storage::ipfs::client::IpfsClient;
+use crate::storage::upload::Uploads;
+use crate::ratelimit::{self, RateLimiter, Limited};
+use crate::webhook;
+use crate::confirm;
+use crate::reconcile::{self, Fix};
+use crate::connection::Connection;
+use crate::extrinsic;
+use crate::auth::{self, Signed};
 use hex as hhex;
 use rocket::logger::LoggingLevel;
 
 
+/// json error body for a request that failed before or outside of its handler,
+/// with the message of a failed request guard when there is one
+fn catch_error(request: &Request, status: Status, code: &str, message: &str) -> status::Custom<content::Json<String>> {
+    let message = GuardError::of(request).unwrap_or_else(|| message.to_owned());
+    status::Custom(status, content::Json(error_body(request, code, &message)))
+}
+
+/// pause between two webhook delivery rounds
+const WEBHOOK_INTERVAL: StdDuration = StdDuration::from_secs(5);
+/// pause between two rounds of confirmations and extrinsic submissions
+const CONFIRM_INTERVAL: StdDuration = StdDuration::from_secs(30);
+/// rounds between two reads of the chain orders
+const TRACK_ROUNDS: u32 = 10;
+/// pause between two looks at a chain connection that is still down
+const CHAIN_WAIT: StdDuration = StdDuration::from_secs(1);
+
+#[catch(400)]
+fn bad_request(request: &Request) -> status::Custom<content::Json<String>> {
+    catch_error(request, Status::BadRequest, "bad_request", "The request could not be understood.")
+}
+
+#[catch(401)]
+fn unauthorized(request: &Request) -> status::Custom<content::Json<String>> {
+    catch_error(request, Status::Unauthorized, "unauthorized", "The request is not authorized.")
+}
+
 #[catch(404)]
-fn not_found() -> JsonValue {
-    json!({
-        "status": "error",
-        "reason": "Resource was not found."
+fn not_found(request: &Request) -> status::Custom<content::Json<String>> {
+    catch_error(request, Status::NotFound, "not_found", "Resource was not found.")
+}
+
+#[catch(413)]
+fn payload_too_large(request: &Request) -> status::Custom<content::Json<String>> {
+    catch_error(request, Status::PayloadTooLarge, "payload_too_large", "The request body is too large.")
+}
+
+#[catch(422)]
+fn unprocessable_entity(request: &Request) -> status::Custom<content::Json<String>> {
+    catch_error(request, Status::UnprocessableEntity, "invalid_body", "The request body could not be parsed.")
+}
+
+/// an address limited by `check_address`, answered like the `rate_limited` route
+#[catch(429)]
+fn too_many_requests(request: &Request) -> MinerError {
+    let limited = request.local_cache(|| Limited(0));
+    MinerError::new(MinerErrorKind::RateLimited(limited.0.max(1)))
+}
+
+#[catch(500)]
+fn internal_error(request: &Request) -> status::Custom<content::Json<String>> {
+    catch_error(request, Status::InternalServerError, "internal_error", "The miner failed to handle the request.")
+}
+
+/// give every response the id of its request
+fn request_id_fairing() -> AdHoc {
+    AdHoc::on_response("Request id", |request, response| {
+        response.set_raw_header(REQUEST_ID_HEADER, RequestId::of(request));
     })
 }
 
+/// value of the `Content-Length` header, if the client sent one
+pub(crate) struct ContentLength(pub Option<u64>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ContentLength {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let length = request.headers().get_one("Content-Length").and_then(|l| l.parse::<u64>().ok());
+        Outcome::Success(ContentLength(length))
+    }
+}
+
+/// a request signed by the address in its path, see `auth`
+pub(crate) struct SignedRequest(pub Signed);
+
+impl<'a, 'r> FromRequest<'a, 'r> for SignedRequest {
+    type Error = MinerError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match signed_request(request) {
+            Ok(signed) => Outcome::Success(SignedRequest(signed)),
+            Err(e) => {
+                log::warn!("{} {} rejected: {}", request.method(), request.uri(), e);
+                GuardError::keep(request, &e);
+                Outcome::Failure((e.status(), e))
+            }
+        }
+    }
+}
+
+fn signed_request(request: &Request) -> Result<Signed> {
+    let headers = request.headers();
+    let header = |name: &str| headers.get_one(name)
+        .ok_or_else(|| auth::unauthorized(format!("missing `{}` header", name)));
+
+    let address = match request.get_param::<String>(0) {
+        Some(Ok(address)) => address,
+        _ => return Err(auth::unauthorized("missing address")),
+    };
+
+    let signed = Signed {
+        address,
+        method: request.method().as_str().to_owned(),
+        path: request.uri().path().to_owned(),
+        body_hash: header(auth::BODY_HASH_HEADER)?.to_lowercase(),
+        timestamp: header(auth::TIMESTAMP_HEADER)?.parse::<i64>()
+            .map_err(|_| auth::unauthorized("timestamp is not a unix timestamp"))?,
+        nonce: header(auth::NONCE_HEADER)?.to_owned(),
+        crypto: headers.get_one(auth::CRYPTO_HEADER).unwrap_or("sr25519").to_owned(),
+        signature: auth::decode_signature(header(auth::SIGNATURE_HEADER)?)?,
+    };
+    signed.verify()?;
+
+    // the address bucket is only taken from by its owner, the nonce stays
+    // unused so the same request may be sent again after the wait
+    if let Some(limiter) = request.guard::<State<RateLimiter>>().succeeded() {
+        if let Some(wait) = limiter.check_address(request.method(), request.uri().path(), &signed.address) {
+            request.local_cache(|| Limited(wait));
+            return Err(MinerError::new(MinerErrorKind::RateLimited(wait)));
+        }
+    }
+
+    let client_config = request.guard::<State<ClientConfig>>().succeeded()
+        .ok_or_else(|| MinerError::msg("client config is not managed"))?;
+    signed.consume_nonce(&client_config.db)?;
+
+    Ok(signed)
+}
+
+/// value of the `Upload-Offset` header of a chunk
+pub(crate) struct UploadOffset(pub u64);
+
+impl<'a, 'r> FromRequest<'a, 'r> for UploadOffset {
+    type Error = MinerError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one("Upload-Offset").and_then(|o| o.parse::<u64>().ok()) {
+            Some(offset) => Outcome::Success(UploadOffset(offset)),
+            None => {
+                let e = MinerError::invalid("missing or invalid `Upload-Offset` header");
+                GuardError::keep(request, &e);
+                Outcome::Failure((Status::BadRequest, e))
+            }
+        }
+    }
+}
+
+/// headers answering a progress request of a resumable upload
+pub(crate) struct UploadProgress {
+    offset: u64,
+    length: u64,
+}
+
+impl<'r> Responder<'r> for UploadProgress {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .raw_header("Upload-Offset", self.offset.to_string())
+            .raw_header("Upload-Length", self.length.to_string())
+            .raw_header("Cache-Control", "no-store")
+            .ok()
+    }
+}
+
+/// conditional and range headers of a data request
+pub(crate) struct DataHeaders {
+    range: Option<String>,
+    if_none_match: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for DataHeaders {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        Outcome::Success(DataHeaders {
+            range: headers.get_one("Range").map(String::from),
+            if_none_match: headers.get_one("If-None-Match").map(String::from),
+        })
+    }
+}
+
+impl DataHeaders {
+    /// whether the client already holds the content tagged with `etag`
+    fn matches(&self, etag: &str) -> bool {
+        match self.if_none_match {
+            Some(ref tags) => tags.split(',').map(|t| t.trim().trim_start_matches("W/")).any(|t| t == "*" || t == etag),
+            None => false,
+        }
+    }
+}
+
+/// content served from ipfs, full or partial
+pub(crate) struct DataResponse {
+    status: Status,
+    etag: String,
+    content_type: ContentType,
+    content_range: Option<String>,
+    body: Option<reqwest::blocking::Response>,
+}
+
+impl<'r> Responder<'r> for DataResponse {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        let mut response = Response::build();
+        response.status(self.status)
+            .header(self.content_type)
+            .raw_header("ETag", self.etag)
+            .raw_header("Accept-Ranges", "bytes")
+            .raw_header("Cache-Control", "public, max-age=31536000, immutable")
+            .raw_header("X-Content-Type-Options", "nosniff")
+            .raw_header("Content-Security-Policy", "default-src 'none'; sandbox");
+
+        if let Some(content_range) = self.content_range {
+            response.raw_header("Content-Range", content_range);
+        }
+        if let Some(body) = self.body {
+            response.streamed_body(body);
+        }
+        response.ok()
+    }
+}
+
 pub(crate) struct ClientConfig {
-    sub_client: Client<IpseRuntime>,
-    kv_database: KVDatabase,
+    chain: Connection,
+    /// the one handle of the database, shared with the background workers
+    db: Arc<Database>,
     ipfs_client: IpfsClient,
+    uploads: Uploads,
     settings: Settings,
     pair: Pair,
+    /// start of the serve, for the uptime
+    started: DateTime<Local>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Encode, Decode)]
@@ -239,6 +1041,51 @@ pub(crate) struct MinerRequest {
     pub st_ctime_nsec: i64,
 }
 
+/// a new resumable upload
+#[derive(Serialize, Deserialize)]
+pub(crate) struct UploadInfo {
+    /// total bytes of the upload
+    length: u64,
+    /// hex blake2b-256 of the complete upload
+    digest: String,
+}
+
+/// query of a price quote
+#[derive(FromForm)]
+pub(crate) struct QuoteQuery {
+    /// bytes to store
+    size: u64,
+    days: u64,
+}
+
+/// days added to an order
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ExtendInfo {
+    days: u64,
+}
+
+/// query of a storage challenge
+#[derive(FromForm)]
+pub(crate) struct ChallengeQuery {
+    /// hex random bytes picking the challenged chunks
+    seed: String,
+    /// number of chunks, 8 by default
+    count: Option<usize>,
+}
+
+/// query of the order listing
+#[derive(FromForm)]
+pub(crate) struct OrderQuery {
+    page: Option<usize>,
+    size: Option<usize>,
+    category: Option<String>,
+    label: Option<String>,
+    /// `time` (default) or `size`
+    sort: Option<String>,
+    /// `desc` (default) or `asc`
+    order: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct DataAddInfo {
     name: String,
@@ -246,6 +1093,9 @@ pub(crate) struct DataAddInfo {
     category: String,
     describe: String,
     days: u64,
+    /// id of the order on chain, when the user already created it
+    #[serde(default)]
+    order_id: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Encode, Decode)]
@@ -256,13 +1106,179 @@ pub(crate) struct DataInfo {
     size: String,
     cumulative_size: String,
     blocks: String,
+    #[serde(default)]
+    links: Option<String>,
+}
+
+/// one field of a verification, `equal` when the claimed, stored and live values agree
+#[derive(Serialize, Debug, Eq, PartialEq)]
+pub(crate) struct FieldDiff {
+    field: &'static str,
+    /// value claimed by the user
+    expected: Option<String>,
+    /// value recorded by the miner at upload time
+    stored: Option<String>,
+    /// value reported by ipfs now
+    actual: Option<String>,
+    equal: bool,
+}
+
+impl FieldDiff {
+    /// fields the user or one of the sources does not know are not compared against it
+    pub(crate) fn new(field: &'static str, expected: Option<String>, stored: Option<String>, actual: Option<String>) -> Self {
+        let known: Vec<&String> = expected.iter().chain(stored.iter()).chain(actual.iter()).collect();
+        let equal = known.windows(2).all(|pair| pair[0] == pair[1]);
+
+        FieldDiff { field, expected, stored, actual, equal }
+    }
 }
 
 
+/// deliver queued webhooks in the background while serving
+fn webhook_worker(settings: &Settings, db: Arc<Database>, pair: &Pair) {
+    if settings.webhooks.urls.is_empty() {
+        return;
+    }
+    let settings = settings.to_owned();
+    let pair = pair.to_owned();
+
+    thread::spawn(move || {
+        loop {
+            thread::sleep(WEBHOOK_INTERVAL);
+            if let Err(e) = webhook::deliver(&db, &settings.webhooks, &pair) {
+                log::warn!("webhook delivery: {}", e);
+            }
+        }
+    });
+}
+
+/// Once the chain is reachable queue the miner profile when it differs and
+/// reconcile the local orders, then keep confirming the chain orders assigned
+/// to the miner and submitting the queued extrinsics in the background while serving.
+fn chain_worker(settings: &Settings, chain: &Connection, db: Arc<Database>, pair: &Pair) {
+    let settings = settings.to_owned();
+    let chain = chain.clone();
+    let pair = pair.to_owned();
+
+    thread::spawn(move || {
+        let ipfs_client = match ipfs_client(&settings) {
+            Ok(ipfs_client) => ipfs_client,
+            Err(_) => return log::error!("chain worker could not start"),
+        };
+
+        let mut synced = false;
+        let mut round = 0;
+        loop {
+            let sub_client = match chain.client() {
+                Some(sub_client) => sub_client,
+                None => {
+                    thread::sleep(CHAIN_WAIT);
+                    continue;
+                }
+            };
+
+            if !synced {
+                // a rejected registration must not keep the miner from serving its data
+                let queued = executor::block_on(registration(&settings, AccountId32::from(pair.public()), &sub_client))
+                    .and_then(|profile| {
+                        if let Registration::Update { ref diffs, .. } = profile {
+                            log::warn!("miner profile differs from the chain: {:?}", diffs);
+                        }
+                        profile.call(&sub_client)
+                    })
+                    .and_then(|call| match call {
+                        Some(call) => extrinsic::queue(&db, call).map(Some),
+                        None => Ok(None),
+                    });
+                match queued {
+                    Ok(Some(id)) => log::info!("miner profile queued as extrinsic {}", id),
+                    Ok(None) => {}
+                    Err(e) => log::error!("miner profile could not be synced: {}", e),
+                }
+
+                // report only, the fixes are left to `miner reconcile`
+                match reconcile::reconcile(&db, &ipfs_client, &pair, &sub_client, &settings, Fix::default()) {
+                    Ok(report) if report.is_clean() => log::info!("local orders agree with the chain"),
+                    Ok(report) => log::warn!(
+                        "local orders drift from the chain: {} missing, {} unpinned, {} orphaned, {} unlinked, {} stray pins, run `miner reconcile`",
+                        report.missing.len(), report.unpinned.len(), report.orphaned.len(), report.unlinked.len(), report.stray_pins.len(),
+                    ),
+                    Err(e) => log::warn!("reconcile with the chain: {}", e),
+                }
+                synced = true;
+            }
+
+            // the whole order list is read from the chain, not on every round
+            if round == 0 {
+                match confirm::track_orders(&db, &pair, &sub_client) {
+                    Ok(tracked) if !tracked.is_empty() => log::info!("chain orders {:?} assigned to the miner", tracked),
+                    Ok(_) => {}
+                    Err(e) => log::warn!("read chain orders: {}", e),
+                }
+            }
+            round = (round + 1) % TRACK_ROUNDS;
+            match confirm::confirm_orders(&db, &ipfs_client, &settings) {
+                Ok(confirmed) if !confirmed.submitted.is_empty() => log::info!("confirming orders {:?}", confirmed.submitted),
+                Ok(_) => {}
+                Err(e) => log::warn!("confirm orders: {}", e),
+            }
+            if let Err(e) = extrinsic::process(&db, &pair, &sub_client, &settings) {
+                log::warn!("submit queued extrinsics: {}", e);
+            }
+            thread::sleep(CONFIRM_INTERVAL);
+        }
+    });
+}
+
 fn cors_fairing() -> Cors {
     Cors::from_options(&Default::default()).expect("Cors fairing cannot be created")
 }
 
+/// the routes, catchers and fairings of the miner, the state is managed by the caller
+fn mount(rocket: rocket::Rocket) -> rocket::Rocket {
+    rocket
+        .mount(
+            "/api/v0",
+            routes![
+                routes::create_order,
+                routes::create_order_info,
+                routes::extend_order,
+                routes::list_orders,
+                routes::order_detail,
+                routes::get_data,
+                routes::challenge,
+                routes::create_upload,
+                routes::append_upload,
+                routes::upload_progress,
+                routes::finalize_upload,
+                routes::delete_order,
+                routes::verify_order,
+                routes::quote,
+                routes::status,
+                routes::rate_limited,
+                routes::usage,
+                routes::address_usage,
+            ],
+        )
+        .mount(
+            "/admin/v0",
+            routes![
+                admin::list_orders,
+                admin::delete_order,
+                admin::expire_order,
+                admin::totals,
+                admin::run_job,
+                admin::webhooks,
+                admin::recent_errors,
+            ],
+        )
+        .manage(RecentErrors::default())
+        .attach(cors_fairing())
+        .attach(request_id_fairing())
+        .attach(ratelimit::fairing())
+        .register(catchers![bad_request, unauthorized, not_found, payload_too_large, unprocessable_entity, too_many_requests, internal_error])
+}
+
 pub fn serve(settings: &Settings, address: &str, port: u16) -> Result<()> {
     let config = Config::build(Environment::Production)
         .address(address)
@@ -272,33 +1288,70 @@ pub fn serve(settings: &Settings, address: &str, port: u16) -> Result<()> {
         .finalize()?;
 
 
-    let seed = settings.miner.secret_seed.as_str();
-    let pair = Pair::from_seed_slice(&hhex::decode(&seed[..])?)?;
+    let pair = miner_pair(settings)?;
+
+    // the http server starts degraded when the node is unreachable
+    let chain = Connection::new(settings);
+    chain.spawn();
+
+    // opened once, every request and worker shares the handle
+    let db = Arc::new(kv_database(settings)?.client()?);
+
+    // orders stored before the usage counters were kept are counted once
+    if order::init_usage(&db)? {
+        log::info!("usage counters built from the stored orders");
+    }
 
     let client_config = ClientConfig {
-        sub_client: sub_client(settings)?,
-        kv_database: kv_database(settings)?,
+        chain: chain.clone(),
+        db: db.clone(),
         ipfs_client: ipfs_client(settings)?,
+        uploads: uploads(settings)?,
         settings: settings.to_owned(),
         pair: pair.to_owned(),
+        started: Local::now(),
     };
 
-    // register_miner
-    executor::block_on(register_miner(settings, pair.to_owned(), sub_client(settings)?))?;
+    webhook_worker(settings, db.clone(), &pair);
 
+    let rate_limit = settings.serve.rate_limit.clone();
+    let rate_db = if rate_limit.persist { Some(db.clone()) } else { None };
+    let rate_limiter = RateLimiter::new(rate_limit, rate_db);
 
-    rocket::custom(config)
-        .mount(
-            "/api/v0",
-            routes![
-                routes::create_order,
-                routes::create_order_info,
-                routes::delete_order,
-                routes::verify_order,
-            ],
-        )
+    chain_worker(settings, &chain, db, &pair);
+
+
+    mount(rocket::custom(config))
         .manage(client_config)
-        .attach(cors_fairing())
-        .register(catchers![not_found]).launch();
+        .manage(rate_limiter)
+        .launch();
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn some(value: &str) -> Option<String> {
+        Some(value.to_owned())
+    }
+
+    #[test]
+    fn test_field_diff() {
+        assert!(FieldDiff::new("hash", some("QmA"), some("QmA"), some("QmA")).equal);
+        assert!(!FieldDiff::new("hash", some("QmA"), some("QmA"), some("QmB")).equal);
+        assert!(!FieldDiff::new("size", some("10"), some("11"), None).equal);
+
+        // a missing value is not compared against
+        assert!(FieldDiff::new("name", some("a"), None, None).equal);
+        assert!(FieldDiff::new("links", None, None, some("2")).equal);
+        assert!(FieldDiff::new("blocks", None, None, None).equal);
+    }
+
+    #[test]
+    fn test_routes() {
+        // colliding routes fail the launch checks
+        let config = Config::build(Environment::Development).finalize().unwrap();
+        assert!(rocket::local::Client::new(mount(rocket::custom(config))).is_ok());
+    }
+}
@@ -0,0 +1,195 @@
+//! Operator api mounted at `/admin/v0`, every route needs the admin token
+//! as `Authorization: Bearer <token>`, see `settings::admin_token`.
+use std::collections::HashSet;
+use chrono::Local;
+use kvdb::KeyValueDB;
+use rocket::{Outcome, State};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, LenientForm, Request};
+use rocket_contrib::json::JsonValue;
+
+use super::ClientConfig;
+use crate::constants::{HASH_COL, UNPAID_COL};
+use crate::error::{GuardError, MinerError, MinerErrorKind, RecentErrors, Result};
+use crate::lifecycle;
+use crate::webhook::{self, Event};
+use crate::settings::admin_token;
+use crate::storage::kv::order;
+
+
+/// a request carrying the admin token
+pub(crate) struct Admin;
+
+/// compare without returning at the first differing byte
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = MinerError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let client_config = match request.guard::<State<ClientConfig>>().succeeded() {
+            Some(client_config) => client_config,
+            None => return Outcome::Failure((Status::InternalServerError, MinerError::msg("client config is not managed"))),
+        };
+        let token = admin_token(&client_config.settings);
+
+        let given = request.headers().get_one("Authorization").and_then(|h| h.strip_prefix("Bearer "));
+        match given {
+            Some(given) if equal(given.trim().as_bytes(), token.as_bytes()) => Outcome::Success(Admin),
+            _ => {
+                let e = MinerError::new(MinerErrorKind::Unauthorized("missing or invalid admin token".to_owned()));
+                log::warn!("{} {} rejected: {}", request.method(), request.uri(), e);
+                GuardError::keep(request, &e);
+                Outcome::Failure((Status::Unauthorized, e))
+            }
+        }
+    }
+}
+
+/// query of the order listing of every address
+#[derive(FromForm)]
+pub(crate) struct AdminOrderQuery {
+    page: Option<usize>,
+    size: Option<usize>,
+    address: Option<String>,
+}
+
+/// orders of every address, newest first
+#[get("/orders?<query..>")]
+pub(crate) fn list_orders(client_config: State<'_, ClientConfig>, _admin: Admin, query: LenientForm<AdminOrderQuery>) -> Result<JsonValue> {
+    let kv_client = &*client_config.db;
+
+    let mut entries = order::list_all(kv_client)?;
+    if let Some(ref address) = query.address {
+        entries.retain(|(a, _)| a == address);
+    }
+    entries.sort_by(|a, b| b.1.time.cmp(&a.1.time));
+
+    let total = entries.len();
+    let page = query.page.unwrap_or(1).max(1);
+    let size = query.size.unwrap_or(50).max(1).min(500);
+    let mut orders = vec![];
+    for (address, entry) in entries.into_iter().skip((page - 1).saturating_mul(size)).take(size) {
+        let mut value = serde_json::to_value(&entry)?;
+        value["address"] = serde_json::Value::String(address);
+        orders.push(value);
+    }
+
+    Ok(json!({
+        "total": total,
+        "page": page,
+        "size": size,
+        "orders": orders,
+    }))
+}
+
+/// delete an order of any address, on chain as well when its id is known
+#[delete("/orders/<address>/<hash>")]
+pub(crate) fn delete_order(client_config: State<'_, ClientConfig>, _admin: Admin, address: String, hash: String) -> Result<JsonValue> {
+    let kv_client = &*client_config.db;
+
+    let deleted = lifecycle::delete_order(
+        kv_client,
+        &client_config.ipfs_client,
+        &address,
+        &hash,
+    )?;
+    log::warn!("admin deleted order {} of {}", hash, address);
+    webhook::emit_logged(kv_client, &client_config.settings, Event::Deleted, &address, &hash, serde_json::to_value(&deleted)?);
+
+    Ok(json!(deleted))
+}
+
+/// expire an order now, the next job run deletes it
+#[post("/orders/<address>/<hash>/expire")]
+pub(crate) fn expire_order(client_config: State<'_, ClientConfig>, _admin: Admin, address: String, hash: String) -> Result<JsonValue> {
+    let kv_client = &*client_config.db;
+    if order::find(kv_client, &address, &hash).is_none() {
+        return Err(MinerError::new(MinerErrorKind::FileNotFound));
+    }
+
+    let info = order::set_expire(kv_client, &address, &hash, &order::now())?;
+    log::warn!("admin expired order {} of {}", hash, address);
+
+    Ok(json!({
+        "address": address,
+        "hash": hash,
+        "info": info,
+    }))
+}
+
+/// storage totals of the whole miner
+#[get("/totals")]
+pub(crate) fn totals(client_config: State<'_, ClientConfig>, _admin: Admin) -> Result<JsonValue> {
+    let kv_client = &*client_config.db;
+    let capacity = client_config.settings.miner.capacity;
+
+    let used = order::usage(kv_client, b"");
+    let addresses: HashSet<_> = order::orders(kv_client, b"").map(|o| o.address).collect();
+
+    Ok(json!({
+        "capacity": capacity,
+        "used": used.bytes,
+        "available": capacity.saturating_sub(used.bytes),
+        "orders": used.objects,
+        "addresses": addresses.len(),
+        "hashes": kv_client.iter(HASH_COL).count(),
+        "unpaid": kv_client.iter(UNPAID_COL).count(),
+        "repo": client_config.ipfs_client.repo_stat().ok(),
+    }))
+}
+
+/// run the miner job now: delete expired orders and abandoned uploads
+#[post("/job")]
+pub(crate) fn run_job(client_config: State<'_, ClientConfig>, recent: State<'_, RecentErrors>, _admin: Admin) -> Result<JsonValue> {
+    let started = Local::now();
+    let kv_client = &*client_config.db;
+
+    let expired = lifecycle::delete_expired(kv_client, &client_config.ipfs_client);
+    let mut errors = expired.errors.clone();
+    for deleted in expired.deleted.iter() {
+        webhook::emit_logged(kv_client, &client_config.settings, Event::Expired, &deleted.address, &deleted.hash, serde_json::to_value(deleted)?);
+    }
+    if let Err(e) = webhook::notify_expiring(kv_client, &client_config.settings) {
+        errors.push(format!("notify expiring orders: {}", e));
+    }
+    let stale_uploads = match client_config.uploads.remove_stale() {
+        Ok(removed) => removed,
+        Err(e) => {
+            errors.push(format!("remove stale uploads: {}", e));
+            0
+        }
+    };
+    for e in errors.iter() {
+        log::error!("admin job error: {}", e);
+        recent.push("job", 500, "job_error", e);
+    }
+
+    Ok(json!({
+        "started": started.format("%Y%m%d%H%M%S").to_string(),
+        "deleted": expired.deleted,
+        "collected": expired.collected,
+        "stale_uploads": stale_uploads,
+        "errors": errors,
+    }))
+}
+
+/// webhook deliveries waiting for a retry and dead-lettered ones
+#[get("/webhooks")]
+pub(crate) fn webhooks(client_config: State<'_, ClientConfig>, _admin: Admin) -> Result<JsonValue> {
+    let kv_client = &*client_config.db;
+    let (queued, dead) = webhook::pending(kv_client);
+
+    Ok(json!({
+        "queued": queued,
+        "dead": dead,
+    }))
+}
+
+/// the last server side errors, newest first
+#[get("/errors")]
+pub(crate) fn recent_errors(recent: State<'_, RecentErrors>, _admin: Admin) -> JsonValue {
+    json!({ "errors": recent.list() })
+}
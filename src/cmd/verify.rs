@@ -0,0 +1,29 @@
+use std::fs;
+use std::io::{self, Read};
+use yansi::Paint;
+
+use crate::error::Result;
+use crate::proof::Proof;
+
+
+/// Check offline a challenge answer saved as json (`-` reads stdin)
+/// against the challenge that was sent and the merkle root kept at upload time.
+pub fn verify_proof(path: &str, root: &str, miner: Option<&str>, hash: &str, seed: &str, count: usize) -> Result<()> {
+    let content = match path {
+        "-" => {
+            let mut content = vec![];
+            io::stdin().read_to_end(&mut content)?;
+            content
+        }
+        path => fs::read(path)?,
+    };
+    let proof: Proof = serde_json::from_slice(&content)?;
+
+    proof.verify(root, miner, hash, seed, count)?;
+    println!("{} {} chunks of {} proven by {}",
+             Paint::green("Valid proof:").bold(),
+             proof.chunks.len(),
+             proof.hash,
+             proof.miner);
+    Ok(())
+}
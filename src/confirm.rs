@@ -0,0 +1,236 @@
+//! Confirmation of the on-chain orders assigned to the miner.
+//!
+//! The chain orders are read now and then, every order holding this miner and
+//! not yet confirmed by it is recorded in `CONFIRM_COL`. The recorded orders
+//! get a confirm extrinsic queued with the miner url once their content is
+//! pinned locally, see `extrinsic`. A record is only written when its state
+//! changes.
+use chrono::Utc;
+use codec::{Encode, Decode};
+use futures::executor;
+use kvdb::KeyValueDB;
+use kvdb_rocksdb::Database;
+use sp_core::{sr25519::Pair, Pair as PairT};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use substrate_subxt::Client;
+
+use crate::chain::{self, IpseRuntime};
+use crate::constants::{CHAIN_COL, CONFIRM_COL};
+use crate::error::Result;
+use crate::extrinsic::{self, Call};
+use crate::settings::Settings;
+use crate::storage::ipfs::client::IpfsClient;
+use crate::storage::kv::order;
+use crate::webhook::{self, Event};
+
+
+/// failed confirmations of an order before it is left alone
+pub const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Serialize, Encode, Decode, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// the content is not pinned on the node yet
+    Unpinned,
+    Confirmed,
+    Failed,
+    /// the chain does not assign the order to the miner, written by older
+    /// reconciliations and replaced once the chain order is read
+    Orphaned,
+    /// the confirm extrinsic waits in the queue
+    Submitted,
+}
+
+/// Confirmation state of one on-chain order.
+#[derive(Serialize, Encode, Decode, Debug, Clone, Eq, PartialEq)]
+pub struct Confirmation {
+    pub order_id: u64,
+    /// ipfs hash of the content
+    pub hash: String,
+    /// ss58 address of the user who placed the order
+    pub address: String,
+    pub state: State,
+    /// hash of the confirm extrinsic once it is in a block
+    pub extrinsic: Option<String>,
+    pub attempts: u32,
+    pub last_error: String,
+    /// unix timestamp of the last change
+    pub updated: i64,
+}
+
+impl Confirmation {
+    /// whether another confirmation may be attempted
+    fn retry(&self) -> bool {
+        match self.state {
+            State::Confirmed | State::Orphaned | State::Submitted => false,
+            State::Failed => self.attempts < MAX_ATTEMPTS,
+            State::Unpinned => true,
+        }
+    }
+}
+
+fn key(order_id: u64) -> Vec<u8> {
+    // big endian so the column iterates in id order
+    order_id.to_be_bytes().to_vec()
+}
+
+pub fn get(db: &Database, order_id: u64) -> Result<Option<Confirmation>> {
+    Ok(match db.get(CONFIRM_COL, &key(order_id))? {
+        Some(value) => Some(Confirmation::decode(&mut &value[..])?),
+        None => None,
+    })
+}
+
+pub fn list(db: &Database) -> Vec<Confirmation> {
+    db.iter(CONFIRM_COL)
+        .filter_map(|(_, value)| Confirmation::decode(&mut &value[..]).ok())
+        .collect()
+}
+
+pub fn put(db: &Database, confirmation: &Confirmation) -> Result<()> {
+    let mut batch = db.transaction();
+    batch.put(CONFIRM_COL, &key(confirmation.order_id), &confirmation.encode());
+    // remember the on-chain id of a local order the user never reported
+    let info_key = order::info_key(&confirmation.address, &confirmation.hash);
+    if confirmation.state == State::Confirmed && db.get(CHAIN_COL, &info_key)?.is_none()
+        && order::find(db, &confirmation.address, &confirmation.hash).is_some() {
+        batch.put(CHAIN_COL, &info_key, &confirmation.order_id.encode());
+    }
+    db.write(batch)?;
+    Ok(())
+}
+
+/// the confirm extrinsic of `order_id` made it into a block
+pub fn included(db: &Database, settings: &Settings, order_id: u64, extrinsic: Option<String>) -> Result<()> {
+    let mut confirmation = match get(db, order_id)? {
+        Some(confirmation) => confirmation,
+        None => return Ok(()),
+    };
+    log::info!("confirmed order {} of {}", order_id, confirmation.hash);
+    confirmation.state = State::Confirmed;
+    confirmation.extrinsic = extrinsic;
+    confirmation.updated = Utc::now().timestamp();
+    put(db, &confirmation)?;
+
+    webhook::emit_logged(db, settings, Event::Confirmed, &confirmation.address, &confirmation.hash, serde_json::to_value(&confirmation)?);
+    Ok(())
+}
+
+/// the confirm extrinsic of `order_id` was given up, the order is tried again
+pub fn dropped(db: &Database, order_id: u64, error: &str) -> Result<()> {
+    let mut confirmation = match get(db, order_id)? {
+        Some(confirmation) => confirmation,
+        None => return Ok(()),
+    };
+    confirmation.state = State::Failed;
+    confirmation.attempts += 1;
+    confirmation.last_error = error.to_owned();
+    confirmation.updated = Utc::now().timestamp();
+    put(db, &confirmation)
+}
+
+/// Outcome of a confirmation run.
+#[derive(Serialize, Debug, Default)]
+pub struct Confirmed {
+    /// orders whose confirm extrinsic was queued
+    pub submitted: Vec<u64>,
+    /// orders waiting for their content
+    pub unpinned: Vec<u64>,
+}
+
+/// Record the chain orders assigned to the miner it did not confirm yet,
+/// returns the ids of the orders recorded by this call.
+pub fn track_orders(db: &Database, pair: &Pair, sub_client: &Client<IpseRuntime>) -> Result<Vec<u64>> {
+    let miner = AccountId32::from(pair.public());
+    let orders = executor::block_on(chain::orders(sub_client))?;
+    let now = Utc::now().timestamp();
+
+    let mut tracked = vec![];
+    let mut batch = db.transaction();
+    for (order_id, o) in orders.into_iter().enumerate() {
+        let order_id = order_id as u64;
+        if !o.orders.iter().any(|m| m.miner == miner && m.url.is_none()) {
+            continue;
+        }
+        let (hash, address) = (String::from_utf8_lossy(&o.key).into_owned(), o.user.to_ss58check());
+        // a record of another content under the id is not this order
+        match get(db, order_id)? {
+            Some(known) if known.hash == hash && known.address == address => continue,
+            Some(known) => log::warn!("order {} was recorded for {}, the chain holds {}", order_id, known.hash, hash),
+            None => {}
+        }
+        let confirmation = Confirmation {
+            order_id,
+            hash,
+            address,
+            state: State::Unpinned,
+            extrinsic: None,
+            attempts: 0,
+            last_error: String::new(),
+            updated: now,
+        };
+        batch.put(CONFIRM_COL, &key(order_id), &confirmation.encode());
+        tracked.push(order_id);
+    }
+    db.write(batch)?;
+    Ok(tracked)
+}
+
+/// Queue the confirmation of the recorded orders whose content is pinned.
+pub fn confirm_orders(db: &Database, ipfs_client: &IpfsClient, settings: &Settings) -> Result<Confirmed> {
+    let mut confirmed = Confirmed::default();
+    for mut confirmation in list(db).into_iter().filter(|c| c.retry()) {
+        let order_id = confirmation.order_id;
+        let pinned = match ipfs_client.pin_ls(&confirmation.hash) {
+            Ok(pinned) => pinned,
+            Err(e) => {
+                log::warn!("pin state of {} unknown: {}", confirmation.hash, e);
+                continue;
+            }
+        };
+
+        if pinned {
+            extrinsic::queue(db, Call::ConfirmOrder { order_id, url: settings.miner.url.clone() })?;
+            confirmation.state = State::Submitted;
+            confirmed.submitted.push(order_id);
+        } else {
+            confirmed.unpinned.push(order_id);
+            if confirmation.state == State::Unpinned {
+                continue;
+            }
+            confirmation.state = State::Unpinned;
+        }
+        confirmation.updated = Utc::now().timestamp();
+        put(db, &confirmation)?;
+    }
+    Ok(confirmed)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let mut confirmation = Confirmation {
+            order_id: 1,
+            hash: "Qm".to_owned(),
+            address: "5A".to_owned(),
+            state: State::Unpinned,
+            extrinsic: None,
+            attempts: 0,
+            last_error: String::new(),
+            updated: 0,
+        };
+        assert!(confirmation.retry());
+
+        confirmation.state = State::Failed;
+        confirmation.attempts = MAX_ATTEMPTS;
+        assert!(!confirmation.retry());
+
+        confirmation.state = State::Confirmed;
+        assert!(!confirmation.retry());
+        assert!(key(1) < key(256));
+    }
+}
@@ -0,0 +1,158 @@
+//! Connection to the chain node.
+//!
+//! The serve does not wait for the node: a background thread connects to the
+//! first reachable url of `chain.url` and `chain.fallback_urls`, backing off
+//! exponentially between rounds, and reconnects once a health check fails
+//! after the websocket dropped. Until then the miner runs degraded, callers
+//! get no client and their extrinsics wait in the queue, see `extrinsic`.
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use chrono::Utc;
+use futures::executor;
+use substrate_subxt::Client;
+
+use crate::chain::IpseRuntime;
+use crate::settings::{Settings, chain_client};
+
+
+/// first wait after a failed connection round, doubled on every round
+const BACKOFF_BASE: u64 = 1;
+/// longest wait between two connection rounds
+const BACKOFF_MAX: u64 = 60;
+/// pause between two health checks of a connected node
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// seconds to wait after `attempts` failed connection rounds
+pub fn backoff(attempts: u32) -> Duration {
+    Duration::from_secs(BACKOFF_BASE.saturating_mul(1u64 << attempts.min(16)).min(BACKOFF_MAX))
+}
+
+/// State of the connection, as shown by the status endpoint.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ChainStatus {
+    pub connected: bool,
+    /// node the miner is connected to
+    pub url: Option<String>,
+    pub best_block: Option<u32>,
+    /// failed connection rounds since the last connection
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// unix timestamp the connection was made or lost
+    pub since: i64,
+}
+
+#[derive(Default)]
+struct Inner {
+    client: Option<Client<IpseRuntime>>,
+    status: ChainStatus,
+}
+
+/// Shared handle on the chain connection, cheap to clone.
+#[derive(Clone)]
+pub struct Connection {
+    urls: Vec<String>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Connection {
+    pub fn new(settings: &Settings) -> Self {
+        Connection {
+            urls: settings.chain.urls(),
+            inner: Arc::new(Mutex::new(Inner::default())),
+        }
+    }
+
+    /// the connected client, `None` while the node is unreachable
+    pub fn client(&self) -> Option<Client<IpseRuntime>> {
+        self.inner.lock().unwrap().client.clone()
+    }
+
+    pub fn status(&self) -> ChainStatus {
+        self.inner.lock().unwrap().status.clone()
+    }
+
+    /// forget the client after a failed call, the connection thread reconnects
+    pub fn disconnected(&self, error: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.client.take().is_some() {
+            log::warn!("chain node {} lost: {}", inner.status.url.clone().unwrap_or_default(), error);
+            inner.status = ChainStatus {
+                last_error: Some(error.to_owned()),
+                since: Utc::now().timestamp(),
+                ..ChainStatus::default()
+            };
+        }
+    }
+
+    /// try every url once, the first reachable one wins
+    fn connect(&self) -> bool {
+        let mut errors = vec![];
+        for url in self.urls.iter() {
+            match chain_client(url) {
+                Ok(client) => {
+                    log::info!("connected to chain node {}", url);
+                    let mut inner = self.inner.lock().unwrap();
+                    inner.client = Some(client);
+                    inner.status = ChainStatus {
+                        connected: true,
+                        url: Some(url.clone()),
+                        since: Utc::now().timestamp(),
+                        ..ChainStatus::default()
+                    };
+                    return true;
+                }
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.status.attempts += 1;
+        inner.status.last_error = Some(errors.join(", "));
+        if inner.status.since == 0 {
+            inner.status.since = Utc::now().timestamp();
+        }
+        log::warn!("chain unreachable, attempt {}: {}", inner.status.attempts, errors.join(", "));
+        false
+    }
+
+    /// ask the node for its best block, a dropped websocket fails here
+    fn check(&self, client: &Client<IpseRuntime>) {
+        match executor::block_on(client.header(None::<sp_core::H256>)) {
+            Ok(Some(header)) => self.inner.lock().unwrap().status.best_block = Some(header.number),
+            Ok(None) => self.disconnected("no best block"),
+            Err(e) => self.disconnected(&e.to_string()),
+        }
+    }
+
+    /// Keep the connection up in a background thread.
+    pub fn spawn(&self) {
+        let connection = self.clone();
+        thread::spawn(move || loop {
+            match connection.client() {
+                Some(client) => {
+                    connection.check(&client);
+                    thread::sleep(CHECK_INTERVAL);
+                }
+                None => {
+                    if !connection.connect() {
+                        thread::sleep(backoff(connection.status().attempts - 1));
+                    }
+                }
+            }
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(Duration::from_secs(1), backoff(0));
+        assert_eq!(Duration::from_secs(8), backoff(3));
+        assert_eq!(Duration::from_secs(BACKOFF_MAX), backoff(40));
+    }
+}
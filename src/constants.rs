@@ -1,6 +1,36 @@
 pub type StorageValue = Vec<u8>;
 
-pub const META_COL: u32 = 4;
+/// number of rocksdb columns
+pub const META_COL: u32 = 14;
 
-pub mod runtime_type {}
+/// uploaded orders, `address ++ hash ++ upload time` -> `Stat`
+pub const ORDER_COL: u32 = 0;
+/// stored data hashes, `hash` -> number of orders holding it
+pub const HASH_COL: u32 = 1;
+/// data hash pay flag, `hash` -> "" until the order info is added
+pub const UNPAID_COL: u32 = 2;
+/// order info, `address ++ hash` -> `OrderInfo`
+pub const INFO_COL: u32 = 3;
+/// on-chain order id, `address ++ hash` -> `u64`
+pub const CHAIN_COL: u32 = 4;
+/// nonces of signed requests, `address ++ nonce` -> unix timestamp
+pub const NONCE_COL: u32 = 5;
+/// files of a directory order, `address ++ hash` -> `Vec<UploadedFile>`
+pub const FILES_COL: u32 = 6;
+/// merkle leaves of single file contents, `hash` -> `Leaves`
+pub const MERKLE_COL: u32 = 7;
+/// expiry extensions of an order, `address ++ hash` -> `Vec<Extension>`
+pub const EXTEND_COL: u32 = 8;
+/// rate limit buckets, `class:ip:<ip>` or `class:address:<address>` -> tokens and update time
+pub const RATE_COL: u32 = 9;
+/// webhook deliveries, `queue:<next attempt>:<id>` and `dead:<id>` -> `Delivery`,
+/// `expiring:<address ++ hash>` -> announced expire time
+pub const WEBHOOK_COL: u32 = 10;
+/// confirmation of on-chain orders assigned to the miner, `order id` -> `Confirmation`
+pub const CONFIRM_COL: u32 = 11;
+/// extrinsics of the miner in submission order, `id` -> `Extrinsic`
+pub const EXTRINSIC_COL: u32 = 12;
+/// bytes and objects stored, `address` -> `Usage`, the empty key holds the whole miner
+pub const USAGE_COL: u32 = 13;
 
+pub mod runtime_type {}
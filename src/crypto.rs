@@ -19,6 +19,14 @@ pub trait Crypto: Sized {
 
   fn to_address<P: Pair>(pair: &P) -> String;
 
+  /// verify a raw signature of the account behind a ss58 `address`
+  fn verify(signature: &[u8], message: &[u8], address: &str) -> bool {
+    match Self::Public::from_ss58check(address) {
+      Ok(public) => Self::Pair::verify_weak(signature, message, public),
+      Err(_) => false,
+    }
+  }
+
 }
 
 pub struct Ed25519;
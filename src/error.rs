@@ -9,14 +9,35 @@ use sp_core::crypto::{SecretStringError, PublicError};
 use codec::Error as CodecError;
 use std::option::NoneError;
 use rocket::config::ConfigError as RocketConfigError;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use serde_json::Error as JsonError;
+use std::io::Cursor;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::storage::ipfs::model::ApiError as IpfsApiError;
 
 #[derive(Debug)]
 pub enum MinerErrorKind {
     FileNotFound,
     CallError,
+    CapacityExceeded(u64),
+    QuotaExceeded(String),
+    Unauthorized(String),
+    Conflict(String),
+    /// seconds until the request may be retried
+    RateLimited(u64),
+    /// a request the client has to fix, answered with 400
+    Invalid(String),
+    /// a service the miner depends on failed, answered with 502
+    Upstream(String),
     Msg(String),
     Io(::std::io::Error),
     Ipfs(IpfsError),
+    IpfsApi(IpfsApiError),
+    Json(JsonError),
     Config(ConfigError),
     SubXt(SubXtError),
     Reqwest(ReqwestError),
@@ -52,8 +73,12 @@ impl fmt::Display for MinerError {
         match self.kind {
             // TODO: other
             MinerErrorKind::Msg(ref message) => write!(f, "{:?}", message),
+            MinerErrorKind::Invalid(ref message) => write!(f, "{}", message),
+            MinerErrorKind::Upstream(ref message) => write!(f, "{}", message),
             MinerErrorKind::Io(ref e) => write!(f, "{:?}", e),
             MinerErrorKind::Ipfs(ref e) => write!(f, "{:?}", e),
+            MinerErrorKind::IpfsApi(ref e) => write!(f, "{}", e),
+            MinerErrorKind::Json(ref e) => write!(f, "{:?}", e),
             MinerErrorKind::Config(ref e) => write!(f, "{:?}", e),
             MinerErrorKind::SubXt(ref e) => write!(f, "{:?}", e),
             MinerErrorKind::Hex(ref e) => write!(f, "{:?}", e),
@@ -65,6 +90,11 @@ impl fmt::Display for MinerError {
             MinerErrorKind::Reqwest(ref e) => write!(f, "{:?}", e),
             MinerErrorKind::FileNotFound => write!(f, "The accessed file does not exist"),
             MinerErrorKind::CallError => write!(f, "The requested method does not exist"),
+            MinerErrorKind::CapacityExceeded(available) => write!(f, "The miner capacity is exceeded, {} bytes available", available),
+            MinerErrorKind::QuotaExceeded(ref message) => write!(f, "The address quota is exceeded, {}", message),
+            MinerErrorKind::Unauthorized(ref message) => write!(f, "The request is not authorized, {}", message),
+            MinerErrorKind::Conflict(ref message) => write!(f, "The request conflicts with the current state, {}", message),
+            MinerErrorKind::RateLimited(wait) => write!(f, "Too many requests, retry in {} seconds", wait),
         }
     }
 }
@@ -74,6 +104,176 @@ impl MinerError {
     pub fn msg(value: impl ToString) -> Self {
         Self { kind: MinerErrorKind::Msg(value.to_string()), source: None }
     }
+
+    /// a request the client has to fix
+    pub fn invalid(value: impl ToString) -> Self {
+        Self { kind: MinerErrorKind::Invalid(value.to_string()), source: None }
+    }
+
+    /// a failure of ipfs, the chain or another service the miner calls
+    pub fn upstream(value: impl ToString) -> Self {
+        Self { kind: MinerErrorKind::Upstream(value.to_string()), source: None }
+    }
+
+    pub fn new(kind: MinerErrorKind) -> Self {
+        Self { kind, source: None }
+    }
+
+    /// http status of the error when it ends a request
+    pub fn status(&self) -> Status {
+        match self.kind {
+            MinerErrorKind::FileNotFound | MinerErrorKind::CallError => Status::NotFound,
+            MinerErrorKind::CapacityExceeded(_) | MinerErrorKind::QuotaExceeded(_) => Status::PayloadTooLarge,
+            MinerErrorKind::Unauthorized(_) => Status::Unauthorized,
+            MinerErrorKind::Conflict(_) => Status::Conflict,
+            MinerErrorKind::RateLimited(_) => Status::TooManyRequests,
+            MinerErrorKind::Invalid(_) | MinerErrorKind::Hex(_) | MinerErrorKind::PublicError(_) => Status::BadRequest,
+            MinerErrorKind::Json(_) => Status::UnprocessableEntity,
+            MinerErrorKind::Ipfs(_) | MinerErrorKind::IpfsApi(_) | MinerErrorKind::SubXt(_)
+            | MinerErrorKind::Reqwest(_) | MinerErrorKind::Upstream(_) => Status::BadGateway,
+            MinerErrorKind::Msg(_) | MinerErrorKind::Io(_) | MinerErrorKind::Codec(_) | MinerErrorKind::None(_)
+            | MinerErrorKind::Config(_) | MinerErrorKind::RocketConfig(_) | MinerErrorKind::SecretString(_) => Status::InternalServerError,
+        }
+    }
+
+    /// machine readable code of the error, stable across releases
+    pub fn code(&self) -> &'static str {
+        match self.kind {
+            MinerErrorKind::FileNotFound => "not_found",
+            MinerErrorKind::CallError => "method_not_found",
+            MinerErrorKind::CapacityExceeded(_) => "capacity_exceeded",
+            MinerErrorKind::QuotaExceeded(_) => "quota_exceeded",
+            MinerErrorKind::Unauthorized(_) => "unauthorized",
+            MinerErrorKind::Conflict(_) => "conflict",
+            MinerErrorKind::RateLimited(_) => "rate_limited",
+            MinerErrorKind::Invalid(_) => "invalid_request",
+            MinerErrorKind::Hex(_) => "invalid_hex",
+            MinerErrorKind::PublicError(_) => "invalid_address",
+            MinerErrorKind::Json(_) => "invalid_json",
+            MinerErrorKind::Ipfs(_) | MinerErrorKind::IpfsApi(_) => "ipfs_error",
+            MinerErrorKind::SubXt(_) => "chain_error",
+            MinerErrorKind::Reqwest(_) | MinerErrorKind::Upstream(_) => "upstream_error",
+            MinerErrorKind::Io(_) => "io_error",
+            MinerErrorKind::Codec(_) => "codec_error",
+            MinerErrorKind::Config(_) | MinerErrorKind::RocketConfig(_) | MinerErrorKind::SecretString(_) => "config_error",
+            MinerErrorKind::Msg(_) | MinerErrorKind::None(_) => "internal_error",
+        }
+    }
+
+    /// message shown to the client
+    pub fn message(&self) -> String {
+        match self.kind {
+            MinerErrorKind::Msg(ref message) | MinerErrorKind::Invalid(ref message)
+            | MinerErrorKind::Upstream(ref message) => message.to_owned(),
+            _ => self.to_string(),
+        }
+    }
+}
+
+
+/// Id of a request, taken from the `X-Request-Id` header or generated,
+/// returned in the same header and in error bodies.
+pub struct RequestId(pub String);
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+impl RequestId {
+    pub fn of(request: &Request) -> String {
+        request.local_cache(|| {
+            let id = request.headers().get_one(REQUEST_ID_HEADER)
+                .filter(|id| !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+                .map(|id| id.to_owned())
+                .unwrap_or_else(|| crate::util::id::PasteID::new(16).to_string());
+            RequestId(id)
+        }).0.clone()
+    }
+}
+
+/// Message of a failed request guard, kept for the catcher of its status.
+pub struct GuardError(pub Option<String>);
+
+impl GuardError {
+    pub fn keep(request: &Request, error: &MinerError) {
+        request.local_cache(|| GuardError(Some(error.message())));
+    }
+
+    pub fn of(request: &Request) -> Option<String> {
+        request.local_cache(|| GuardError(None)).0.clone()
+    }
+}
+
+/// most errors kept by `RecentErrors`
+pub const RECENT_ERRORS: usize = 100;
+
+/// An error kept for the admin api.
+#[derive(Serialize, Debug, Clone)]
+pub struct ErrorRecord {
+    /// `%Y%m%d%H%M%S`
+    pub time: String,
+    /// request id, or the name of the job that failed
+    pub source: String,
+    pub status: u16,
+    pub code: String,
+    pub message: String,
+}
+
+/// The last server side errors of the miner, managed by the rocket instance.
+#[derive(Default)]
+pub struct RecentErrors(Mutex<VecDeque<ErrorRecord>>);
+
+impl RecentErrors {
+    pub fn push(&self, source: &str, status: u16, code: &str, message: &str) {
+        let mut errors = self.0.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(ErrorRecord {
+            time: chrono::Local::now().format("%Y%m%d%H%M%S").to_string(),
+            source: source.to_owned(),
+            status,
+            code: code.to_owned(),
+            message: message.to_owned(),
+        });
+    }
+
+    /// newest first
+    pub fn list(&self) -> Vec<ErrorRecord> {
+        self.0.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+/// the json body of every error response
+pub fn error_body(request: &Request, code: &str, message: &str) -> String {
+    serde_json::json!({
+        "status": "error",
+        "code": code,
+        "message": message,
+        "request_id": RequestId::of(request),
+    }).to_string()
+}
+
+impl<'r> Responder<'r> for MinerError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let status = self.status();
+        if status.code >= 500 {
+            log::error!("{} {} failed: {}", request.method(), request.uri(), self);
+            if let Some(recent) = request.guard::<rocket::State<RecentErrors>>().succeeded() {
+                let source = format!("{} {} {}", RequestId::of(request), request.method(), request.uri());
+                recent.push(&source, status.code, self.code(), &self.message());
+            }
+        } else {
+            log::warn!("{} {} rejected: {}", request.method(), request.uri(), self);
+        }
+
+        let mut response = Response::build();
+        response.status(status)
+            .header(ContentType::JSON)
+            .sized_body(Cursor::new(error_body(request, self.code(), &self.message())));
+        if let MinerErrorKind::RateLimited(wait) = self.kind {
+            response.raw_header("Retry-After", wait.to_string());
+        }
+        response.ok()
+    }
 }
 
 
@@ -95,6 +295,18 @@ impl From<IpfsError> for MinerError {
     }
 }
 
+impl From<IpfsApiError> for MinerError {
+    fn from(e: IpfsApiError) -> Self {
+        Self { kind: MinerErrorKind::IpfsApi(e), source: None }
+    }
+}
+
+impl From<JsonError> for MinerError {
+    fn from(e: JsonError) -> Self {
+        Self { kind: MinerErrorKind::Json(e), source: None }
+    }
+}
+
 impl From<ConfigError> for MinerError {
     fn from(e: ConfigError) -> Self {
         Self { kind: MinerErrorKind::Config(e), source: None }
@@ -0,0 +1,385 @@
+//! Persistent queue of the extrinsics of the miner.
+//!
+//! Every extrinsic is kept in `EXTRINSIC_COL` and submitted in queue order
+//! with a nonce assigned locally, so concurrent submissions never collide.
+//! A submitted extrinsic follows the nonce of the miner account: it is in a
+//! block once the best nonce passed it and finalized once the finalized nonce
+//! did. The nonce does not tell whether the call succeeded, an included order
+//! confirmation is failed when the chain order still holds no url of the
+//! miner, and the order is confirmed again. An extrinsic failing to submit,
+//! or left in the pool for `READY_TIMEOUT`, is submitted again up to
+//! `MAX_ATTEMPTS` times, then dropped. One left in the pool is signed again
+//! with its own nonce, so it cannot land twice.
+use chrono::Utc;
+use codec::{Encode, Decode};
+use futures::executor;
+use kvdb::KeyValueDB;
+use kvdb_rocksdb::Database;
+use sp_core::{sr25519::Pair, Pair as PairT};
+use sp_core::crypto::AccountId32;
+use substrate_subxt::Client;
+
+use crate::chain::{self, IpseRuntime, Signer};
+use crate::confirm;
+use crate::constants::EXTRINSIC_COL;
+use crate::error::Result;
+use crate::settings::Settings;
+
+
+/// failed submissions of an extrinsic before it is dropped
+pub const MAX_ATTEMPTS: u32 = 10;
+/// seconds a submitted extrinsic may wait in the pool before it is submitted again
+const READY_TIMEOUT: i64 = 10 * 60;
+/// seconds finalized, dropped and failed extrinsics are kept for the status
+const KEEP: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Serialize, Encode, Decode, Debug, Clone, Eq, PartialEq)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum Call {
+    /// register with the profile of the settings
+    RegisterMiner,
+    /// push the profile of the settings
+    UpdateMiner,
+    ConfirmOrder { order_id: u64, url: String },
+    DeleteOrder { order_id: u64 },
+}
+
+#[derive(Serialize, Encode, Decode, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// waiting for its turn or for the node
+    Queued,
+    /// accepted by the node, waiting in the pool
+    Ready,
+    InBlock,
+    Finalized,
+    /// given up after `MAX_ATTEMPTS` failures
+    Dropped,
+    /// included, but the chain did not apply it
+    Failed,
+}
+
+impl State {
+    pub fn is_done(self) -> bool {
+        self == State::Finalized || self == State::Dropped || self == State::Failed
+    }
+
+    fn is_included(self) -> bool {
+        self == State::InBlock || self == State::Finalized
+    }
+}
+
+/// One extrinsic of the queue.
+#[derive(Serialize, Encode, Decode, Debug, Clone, Eq, PartialEq)]
+pub struct Extrinsic {
+    pub id: u64,
+    #[serde(flatten)]
+    pub call: Call,
+    pub state: State,
+    /// nonce of the last submission
+    pub nonce: Option<u32>,
+    /// hash of the last submission
+    pub hash: Option<String>,
+    pub attempts: u32,
+    pub last_error: String,
+    /// unix timestamp the extrinsic was queued
+    pub created: i64,
+    /// unix timestamp of the last submission or state change
+    pub updated: i64,
+}
+
+fn key(id: u64) -> Vec<u8> {
+    // big endian so the column iterates in queue order
+    id.to_be_bytes().to_vec()
+}
+
+pub fn list(db: &Database) -> Vec<Extrinsic> {
+    db.iter(EXTRINSIC_COL)
+        .filter_map(|(_, value)| Extrinsic::decode(&mut &value[..]).ok())
+        .collect()
+}
+
+/// Queue `call` behind the waiting extrinsics, an identical call still
+/// pending is not queued twice. Returns the id of the extrinsic.
+pub fn queue(db: &Database, call: Call) -> Result<u64> {
+    let extrinsics = list(db);
+    if let Some(pending) = extrinsics.iter().find(|e| !e.state.is_done() && e.call == call) {
+        return Ok(pending.id);
+    }
+
+    let now = Utc::now().timestamp();
+    let extrinsic = Extrinsic {
+        id: extrinsics.last().map(|last| last.id + 1).unwrap_or(0),
+        call,
+        state: State::Queued,
+        nonce: None,
+        hash: None,
+        attempts: 0,
+        last_error: String::new(),
+        created: now,
+        updated: now,
+    };
+
+    let mut batch = db.transaction();
+    batch.put(EXTRINSIC_COL, &key(extrinsic.id), &extrinsic.encode());
+    db.write(batch)?;
+    log::info!("extrinsic {} {:?} queued", extrinsic.id, extrinsic.call);
+    Ok(extrinsic.id)
+}
+
+/// Number of extrinsics in every state.
+#[derive(Serialize, Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Summary {
+    pub queued: usize,
+    pub ready: usize,
+    pub in_block: usize,
+    pub finalized: usize,
+    pub dropped: usize,
+    pub failed: usize,
+}
+
+pub fn summary(extrinsics: &[Extrinsic]) -> Summary {
+    extrinsics.iter().fold(Summary::default(), |mut summary, extrinsic| {
+        match extrinsic.state {
+            State::Queued => summary.queued += 1,
+            State::Ready => summary.ready += 1,
+            State::InBlock => summary.in_block += 1,
+            State::Finalized => summary.finalized += 1,
+            State::Dropped => summary.dropped += 1,
+            State::Failed => summary.failed += 1,
+        }
+        summary
+    })
+}
+
+/// Move the submitted extrinsics along the nonces of the miner account.
+/// An extrinsic left in the pool too long is queued again, together with the
+/// ones submitted after it, which cannot be included before it. They keep
+/// their nonce, so a transaction still in the pool is never doubled by a
+/// resubmission, and one queued again whose nonce is passed was included.
+fn track(extrinsics: &mut [Extrinsic], best_nonce: u32, finalized_nonce: u32, now: i64) {
+    let mut stale: Option<u32> = None;
+    for extrinsic in extrinsics.iter_mut().filter(|e| e.state == State::Ready || e.state == State::InBlock || e.state == State::Queued) {
+        let nonce = match extrinsic.nonce {
+            Some(nonce) => nonce,
+            None => continue,
+        };
+        let state = if nonce < finalized_nonce {
+            State::Finalized
+        } else if nonce < best_nonce {
+            State::InBlock
+        } else if extrinsic.state == State::Queued {
+            // waits for its resubmission with the same nonce
+            continue;
+        } else {
+            // back in the pool after a reorg
+            State::Ready
+        };
+        if state != extrinsic.state {
+            extrinsic.state = state;
+            extrinsic.updated = now;
+        } else if state == State::Ready && now - extrinsic.updated > READY_TIMEOUT {
+            stale = Some(stale.map_or(nonce, |s| s.min(nonce)));
+        }
+    }
+
+    let stale = match stale {
+        Some(stale) => stale,
+        None => return,
+    };
+    for extrinsic in extrinsics.iter_mut().filter(|e| e.state == State::Ready && e.nonce.map_or(false, |n| n >= stale)) {
+        if extrinsic.nonce == Some(stale) {
+            extrinsic.attempts += 1;
+            extrinsic.last_error = format!("not included after {}s", READY_TIMEOUT);
+        }
+        extrinsic.state = if extrinsic.attempts >= MAX_ATTEMPTS { State::Dropped } else { State::Queued };
+        extrinsic.updated = now;
+    }
+}
+
+/// First nonce free for an extrinsic never submitted, above every nonce the
+/// submitted ones hold.
+fn next_nonce(extrinsics: &[Extrinsic], best_nonce: u32) -> u32 {
+    extrinsics.iter()
+        .filter(|e| e.state == State::Ready || e.state == State::Queued)
+        .filter_map(|e| e.nonce.map(|n| n + 1))
+        .max()
+        .unwrap_or(0)
+        .max(best_nonce)
+}
+
+/// whether a resubmission failed because its transaction is still in the
+/// pool or was included already
+fn still_submitted(error: &str) -> bool {
+    let error = error.to_lowercase();
+    error.contains("already imported") || error.contains("priority is too low") || error.contains("outdated")
+}
+
+fn submit(call: &Call, signer: &Signer, sub_client: &Client<IpseRuntime>, settings: &Settings) -> Result<sp_core::H256> {
+    executor::block_on(async {
+        match call {
+            Call::RegisterMiner => chain::register_miner(settings, signer, sub_client).await,
+            Call::UpdateMiner => chain::update_miner(settings, signer, sub_client).await,
+            Call::ConfirmOrder { order_id, url } => chain::confirm_order(signer, sub_client, *order_id, url.clone()).await,
+            Call::DeleteOrder { order_id } => chain::delete_order(signer, sub_client, *order_id).await,
+        }
+    })
+}
+
+/// Track the submitted extrinsics, then submit the queued ones in order,
+/// stopping at the first failure so that later ones never overtake it.
+pub fn process(db: &Database, pair: &Pair, sub_client: &Client<IpseRuntime>, settings: &Settings) -> Result<Summary> {
+    let pending: Vec<_> = list(db).into_iter().filter(|e| !e.state.is_done()).collect();
+    if pending.is_empty() {
+        return Ok(summary(&list(db)));
+    }
+
+    let account = AccountId32::from(pair.public());
+    let best_nonce = executor::block_on(chain::account_nonce(sub_client, &account, None))?;
+    let finalized = executor::block_on(chain::finalized_head(sub_client))?;
+    let finalized_nonce = executor::block_on(chain::account_nonce(sub_client, &account, Some(finalized)))?;
+    let now = Utc::now().timestamp();
+
+    let mut extrinsics = pending.clone();
+    track(&mut extrinsics, best_nonce, finalized_nonce, now);
+
+    // a confirmation is only taken once the chain order holds the url of the miner
+    let included: Vec<u64> = pending.iter().zip(extrinsics.iter())
+        .filter(|(before, e)| e.state.is_included() && !before.state.is_included())
+        .filter(|(_, e)| matches!(e.call, Call::ConfirmOrder { .. }))
+        .map(|(_, e)| e.id)
+        .collect();
+    if !included.is_empty() {
+        let orders = executor::block_on(chain::orders(sub_client))?;
+        for extrinsic in extrinsics.iter_mut().filter(|e| included.contains(&e.id)) {
+            let order_id = match extrinsic.call {
+                Call::ConfirmOrder { order_id, .. } => order_id,
+                _ => continue,
+            };
+            let confirmed = orders.get(order_id as usize)
+                .map_or(false, |o| o.orders.iter().any(|m| m.miner == account && m.url.is_some()));
+            if !confirmed {
+                extrinsic.state = State::Failed;
+                extrinsic.last_error = "included, but the order holds no url of the miner".to_owned();
+                log::error!("extrinsic {} {:?} failed: {}", extrinsic.id, extrinsic.call, extrinsic.last_error);
+            }
+        }
+    }
+
+    let mut nonce = next_nonce(&extrinsics, best_nonce);
+    let mut signer = Signer::new(pair.to_owned());
+    for extrinsic in extrinsics.iter_mut().filter(|e| e.state == State::Queued) {
+        // a stale extrinsic is signed again with its own nonce
+        let resubmit = extrinsic.nonce;
+        signer.set_nonce(resubmit.unwrap_or(nonce));
+        extrinsic.updated = now;
+        match submit(&extrinsic.call, &signer, sub_client, settings) {
+            Ok(hash) => {
+                log::info!("extrinsic {} {:?} submitted with nonce {}", extrinsic.id, extrinsic.call, resubmit.unwrap_or(nonce));
+                extrinsic.state = State::Ready;
+                extrinsic.hash = Some(format!("{:?}", hash));
+                if resubmit.is_none() {
+                    extrinsic.nonce = Some(nonce);
+                    nonce += 1;
+                }
+            }
+            Err(e) if resubmit.is_some() && still_submitted(&e.to_string()) => {
+                log::info!("extrinsic {} {:?} is still submitted: {}", extrinsic.id, extrinsic.call, e);
+                extrinsic.state = State::Ready;
+            }
+            Err(e) => {
+                extrinsic.attempts += 1;
+                extrinsic.last_error = e.to_string();
+                if extrinsic.attempts >= MAX_ATTEMPTS {
+                    log::error!("extrinsic {} {:?} dropped: {}", extrinsic.id, extrinsic.call, extrinsic.last_error);
+                    extrinsic.state = State::Dropped;
+                } else {
+                    log::warn!("extrinsic {} {:?} failed: {}", extrinsic.id, extrinsic.call, extrinsic.last_error);
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut batch = db.transaction();
+    for (before, extrinsic) in pending.iter().zip(extrinsics.iter()) {
+        if before == extrinsic {
+            continue;
+        }
+        batch.put(EXTRINSIC_COL, &key(extrinsic.id), &extrinsic.encode());
+
+        if let Call::ConfirmOrder { order_id, .. } = extrinsic.call {
+            let outcome = if extrinsic.state.is_included() && !before.state.is_included() {
+                confirm::included(db, settings, order_id, extrinsic.hash.clone())
+            } else if extrinsic.state == State::Dropped || extrinsic.state == State::Failed {
+                confirm::dropped(db, order_id, &extrinsic.last_error)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = outcome {
+                log::error!("record confirmation of order {}: {}", order_id, e);
+            }
+        }
+    }
+    for extrinsic in list(db).iter().filter(|e| e.state.is_done() && now - e.updated > KEEP) {
+        batch.delete(EXTRINSIC_COL, &key(extrinsic.id));
+    }
+    db.write(batch)?;
+
+    Ok(summary(&list(db)))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn extrinsic(id: u64, state: State, nonce: Option<u32>, updated: i64) -> Extrinsic {
+        Extrinsic {
+            id,
+            call: Call::DeleteOrder { order_id: id },
+            state,
+            nonce,
+            hash: None,
+            attempts: 0,
+            last_error: String::new(),
+            created: 0,
+            updated,
+        }
+    }
+
+    #[test]
+    fn test_track() {
+        let mut extrinsics = vec![
+            extrinsic(0, State::InBlock, Some(4), 0),
+            extrinsic(1, State::Ready, Some(5), 0),
+            extrinsic(2, State::Ready, Some(6), 0),
+            extrinsic(3, State::Queued, None, 0),
+        ];
+        track(&mut extrinsics, 6, 5, 10);
+        let states: Vec<_> = extrinsics.iter().map(|e| e.state).collect();
+        assert_eq!(vec![State::Finalized, State::InBlock, State::Ready, State::Queued], states);
+
+        // nonce 6 is stale, it is submitted again with the same nonce
+        track(&mut extrinsics, 6, 5, 10 + READY_TIMEOUT + 1);
+        assert_eq!(State::Queued, extrinsics[2].state);
+        assert_eq!(Some(6), extrinsics[2].nonce);
+        assert_eq!(1, extrinsics[2].attempts);
+        assert_eq!(7, next_nonce(&extrinsics, 6));
+
+        let summary = summary(&extrinsics);
+        assert_eq!((2, 1, 1), (summary.queued, summary.in_block, summary.finalized));
+
+        // the first submission landed after all
+        track(&mut extrinsics, 7, 5, 10 + READY_TIMEOUT + 2);
+        assert_eq!(State::InBlock, extrinsics[2].state);
+        assert_eq!(State::Queued, extrinsics[3].state);
+    }
+
+    #[test]
+    fn test_still_submitted() {
+        assert!(still_submitted("Rpc error: Transaction Already Imported"));
+        assert!(still_submitted("1014: Priority is too low"));
+        assert!(!still_submitted("Inability to pay some fees"));
+    }
+}
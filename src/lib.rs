@@ -28,6 +28,16 @@ mod color;
 mod crypto;
 mod pkcs8;
 mod account;
+mod auth;
+mod lifecycle;
+mod proof;
+mod pricing;
+mod ratelimit;
+mod webhook;
+mod confirm;
+mod reconcile;
+mod connection;
+mod extrinsic;
 
 
 
@@ -0,0 +1,120 @@
+//! Order lifecycle actions shared by the http routes and the job runner.
+use chrono::Local;
+use codec::Decode;
+use kvdb::KeyValueDB;
+use kvdb_rocksdb::Database;
+
+use crate::constants::INFO_COL;
+use crate::error::{MinerError, MinerErrorKind, Result};
+use crate::extrinsic::{self, Call};
+use crate::storage::ipfs::client::IpfsClient;
+use crate::storage::kv::order::{self, OrderInfo};
+
+
+/// Outcome of deleting an order.
+#[derive(Serialize, Debug)]
+pub struct Deleted {
+    pub address: String,
+    pub hash: String,
+    /// upload records removed from the database
+    pub records: u64,
+    /// orders of other addresses still holding the hash
+    pub references: u64,
+    pub unpinned: bool,
+    pub order_id: Option<u64>,
+    /// id of the queued on-chain deletion, see `extrinsic`
+    pub extrinsic: Option<u64>,
+    /// steps that failed after the database was updated
+    pub errors: Vec<String>,
+}
+
+/// Delete the order of `address` for `hash`.
+///
+/// The database records are removed in one transaction, the data is unpinned
+/// once no other order holds it and the deletion of the on-chain order is
+/// queued when its id is known.
+pub fn delete_order(
+    db: &Database,
+    ipfs_client: &IpfsClient,
+    address: &str,
+    hash: &str,
+) -> Result<Deleted> {
+    if order::find(db, address, hash).is_none() {
+        return Err(MinerError::new(MinerErrorKind::FileNotFound));
+    }
+
+    let removed = order::remove(db, address, hash)?;
+
+    let mut deleted = Deleted {
+        address: address.to_owned(),
+        hash: hash.to_owned(),
+        records: removed.records,
+        references: removed.references,
+        unpinned: false,
+        order_id: removed.order_id,
+        extrinsic: None,
+        errors: vec![],
+    };
+
+    if removed.references == 0 {
+        match ipfs_client.pin_rm(hash) {
+            Ok(_) => deleted.unpinned = true,
+            Err(e) => deleted.errors.push(format!("unpin: {}", e)),
+        }
+    }
+
+    if let Some(order_id) = removed.order_id {
+        match extrinsic::queue(db, Call::DeleteOrder { order_id }) {
+            Ok(id) => deleted.extrinsic = Some(id),
+            Err(e) => deleted.errors.push(format!("queue chain deletion: {}", e)),
+        }
+    }
+
+    for e in deleted.errors.iter() {
+        log::warn!("delete order {} of {}: {}", hash, address, e);
+    }
+    Ok(deleted)
+}
+
+
+/// Outcome of deleting the expired orders.
+#[derive(Serialize, Debug, Default)]
+pub struct Expired {
+    pub deleted: Vec<Deleted>,
+    pub errors: Vec<String>,
+    /// whether the ipfs garbage collector ran
+    pub collected: bool,
+}
+
+/// Delete every order whose expire time passed, then collect the
+/// unpinned blocks once.
+pub fn delete_expired(
+    db: &Database,
+    ipfs_client: &IpfsClient,
+) -> Expired {
+    let select_key = Local::now().format("%Y%m%d%H%M%S").to_string();
+
+    let expired: Vec<_> = db.iter(INFO_COL).filter_map(|(key, value)|
+        OrderInfo::decode(&mut &value[..]).ok().map(|info| (key, info))
+    ).filter(|(_, info)|
+        info.expire.as_str() <= select_key.as_str()
+    ).filter_map(|(key, _)|
+        order::orders(db, &key).next()
+    ).collect();
+
+    let mut result = Expired::default();
+    for expired_order in expired {
+        match delete_order(db, ipfs_client, &expired_order.address, &expired_order.hash) {
+            Ok(deleted) => result.deleted.push(deleted),
+            Err(e) => result.errors.push(format!("delete {} of {}: {}", expired_order.hash, expired_order.address, e)),
+        }
+    }
+
+    if result.deleted.iter().any(|d| d.unpinned) {
+        match ipfs_client.repo_gc() {
+            Ok(_) => result.collected = true,
+            Err(e) => result.errors.push(format!("ipfs repo gc: {}", e)),
+        }
+    }
+    result
+}
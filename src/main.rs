@@ -23,7 +23,8 @@ use env_logger::{fmt::Color, Builder, Env};
 
 use crate::cmd::{serve, init, job, generate};
 use crate::settings::Settings;
-use crate::error::log_backtrace;
+use crate::error::{log_backtrace, MinerError};
+use crate::reconcile::Fix;
 
 
 mod cli;
@@ -35,6 +36,17 @@ mod storage;
 mod chain;
 mod util;
 mod color;
+mod crypto;
+mod auth;
+mod lifecycle;
+mod proof;
+mod pricing;
+mod ratelimit;
+mod webhook;
+mod confirm;
+mod reconcile;
+mod connection;
+mod extrinsic;
 
 
 fn init_logger() {
@@ -149,6 +161,55 @@ fn main() {
             let settings = Settings::build(config_file).unwrap();
             job(&settings)
         }
+        ("admin-token", Some(_)) => {
+            let settings = Settings::build(config_file).unwrap();
+            println!("{}", settings::admin_token(&settings));
+            Ok(())
+        }
+        ("verify-proof", Some(matches)) => {
+            matches.value_of("count").unwrap_or_default().parse::<usize>()
+                .map_err(|_| MinerError::invalid("count must be a number"))
+                .and_then(|count| cmd::verify_proof(
+                    matches.value_of("proof").unwrap(),
+                    matches.value_of("merkle-root").unwrap(),
+                    matches.value_of("miner"),
+                    matches.value_of("hash").unwrap(),
+                    matches.value_of("seed").unwrap(),
+                    count,
+                ))
+        }
+        ("register", Some(matches)) => {
+            let settings = Settings::build(config_file).unwrap();
+            cmd::register(&settings, matches.is_present("dry-run"), matches.is_present("json"))
+        }
+        ("chain", Some(matches)) => {
+            let settings = Settings::build(config_file).unwrap();
+            let (command, matches) = matches.subcommand();
+            let matches = matches.unwrap();
+            let as_json = matches.is_present("json");
+            let order_id = || matches.value_of("order_id").unwrap_or_default().parse::<u64>()
+                .map_err(|_| MinerError::invalid("order id must be a number"));
+
+            match command {
+                "info" => cmd::chain_info(&settings, as_json),
+                "orders" => cmd::chain_orders(&settings, matches.is_present("mine"), as_json),
+                "confirm" => order_id().and_then(|id| cmd::chain_confirm(&settings, id, as_json)),
+                "delete" => order_id().and_then(|id| cmd::chain_delete(&settings, id, as_json)),
+                "queue" => cmd::chain_queue(&settings, as_json),
+                _ => unreachable!(),
+            }
+        }
+        ("reconcile", Some(matches)) => {
+            let settings = Settings::build(config_file).unwrap();
+            let all = matches.is_present("fix");
+            let fix = Fix {
+                repin: all || matches.is_present("repin"),
+                // other content may share the node, unpinning is never implied
+                unpin: matches.is_present("unpin") && matches.is_present("confirm-unpin"),
+                mark: all || matches.is_present("mark"),
+            };
+            cmd::reconcile(&settings, fix, matches.is_present("json"))
+        }
         _ => unreachable!(),
     };
     if let Err(e) = res {
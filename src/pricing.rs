@@ -0,0 +1,134 @@
+//! Storage price of the miner.
+//!
+//! `miner.unit_price` is registered on chain as it is, in plancks of one
+//! KiB stored for one day, started KiBs are charged in full. Prices are
+//! shown in the units of `chain.decimals` and `chain.symbol`.
+use codec::Encode;
+use chrono::Utc;
+use sp_core::{sr25519::Pair, Pair as PairT};
+
+use crate::crypto::{Crypto, Sr25519, Ss58Codec};
+use crate::error::{MinerError, Result};
+use crate::settings::Chain;
+
+
+/// seconds a quote stays binding
+pub const QUOTE_TTL: i64 = 10 * 60;
+
+/// plancks charged for storing `size` bytes for `days` days
+pub fn price(unit_price: u64, size: u64, days: u64) -> u128 {
+    let kib = (size as u128 + 1023) / 1024;
+    (unit_price as u128).saturating_mul(kib).saturating_mul(days as u128)
+}
+
+/// plancks in the units of `chain`, `1500000000000000` is `15 POST` with 14 decimals
+pub fn format_balance(plancks: u128, chain: &Chain) -> String {
+    let unit = 10u128.pow(chain.decimals);
+    let fraction = format!("{:0width$}", plancks % unit, width = chain.decimals as usize);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        format!("{} {}", plancks / unit, chain.symbol)
+    } else {
+        format!("{}.{} {}", plancks / unit, fraction, chain.symbol)
+    }
+}
+
+/// A price signed by the miner, binding until `expire`.
+#[derive(Serialize, Deserialize, Encode, Debug, Clone, Eq, PartialEq)]
+pub struct Quote {
+    pub size: u64,
+    pub days: u64,
+    pub unit_price: u64,
+    /// price in plancks, a decimal string as it may not fit a json number
+    pub price: String,
+    /// price in human units
+    #[codec(skip)]
+    pub display: String,
+    /// unix timestamp until the quote is binding
+    pub expire: i64,
+    /// ss58 address of the miner
+    pub miner: String,
+    /// hex sr25519 signature of the miner over `message`
+    #[codec(skip)]
+    pub signature: String,
+}
+
+impl Quote {
+    pub fn new(pair: &Pair, chain: &Chain, unit_price: u64, size: u64, days: u64) -> Quote {
+        let price = price(unit_price, size, days);
+        let mut quote = Quote {
+            size,
+            days,
+            unit_price,
+            price: price.to_string(),
+            display: format_balance(price, chain),
+            expire: Utc::now().timestamp() + QUOTE_TTL,
+            miner: pair.public().to_ss58check(),
+            signature: String::new(),
+        };
+        quote.signature = hex::encode(pair.sign(&quote.message()).0.as_ref());
+        quote
+    }
+
+    /// the bytes signed by the miner, the scale encoding of all fields but `display` and the signature
+    pub fn message(&self) -> Vec<u8> {
+        self.encode()
+    }
+
+    /// check the signature and that the quote is still binding
+    pub fn verify(&self) -> Result<()> {
+        let signature = hex::decode(self.signature.trim_start_matches("0x"))
+            .map_err(|_| MinerError::invalid("signature is not hex encoded"))?;
+        if !Sr25519::verify(&signature, &self.message(), &self.miner) {
+            return Err(MinerError::invalid("invalid miner signature"));
+        }
+        if self.expire < Utc::now().timestamp() {
+            return Err(MinerError::invalid("quote expired"));
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_price() {
+        assert_eq!(0, price(100, 0, 30));
+        assert_eq!(100, price(100, 1, 1));
+        assert_eq!(2 * 100 * 30, price(100, 1025, 30));
+    }
+
+    fn chain(decimals: u32, symbol: &str) -> Chain {
+        Chain {
+            url: String::new(),
+            fallback_urls: vec![],
+            decimals,
+            symbol: symbol.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_format_balance() {
+        let post = chain(14, "POST");
+        assert_eq!("0 POST", format_balance(0, &post));
+        assert_eq!("15 POST", format_balance(1_500_000_000_000_000, &post));
+        assert_eq!("0.00000000006 POST", format_balance(6000, &post));
+        assert_eq!("1.5 UNIT", format_balance(1500, &chain(3, "UNIT")));
+        assert_eq!("1500 UNIT", format_balance(1500, &chain(0, "UNIT")));
+    }
+
+    #[test]
+    fn test_quote() {
+        let (pair, _) = Pair::generate();
+        let quote = Quote::new(&pair, &chain(14, "POST"), 100, 2048, 30);
+        assert_eq!("6000", quote.price);
+        assert!(quote.verify().is_ok());
+
+        let mut tampered = quote.clone();
+        tampered.price = "1".to_owned();
+        assert!(tampered.verify().is_err());
+    }
+}
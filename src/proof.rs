@@ -0,0 +1,204 @@
+//! Proof-of-storage answers to a challenge.
+//!
+//! A challenger sends a random seed, the miner answers with the chunks picked
+//! by the seed, their blake2b-256 and Merkle path, signed with the miner key.
+//! Anyone who kept the Merkle root at upload time can check the answer offline.
+use std::io::Read;
+use codec::Encode;
+use chrono::Utc;
+use sp_core::{sr25519::Pair, Pair as PairT};
+
+use crate::crypto::{Crypto, Sr25519, Ss58Codec};
+use crate::error::{MinerError, Result};
+use crate::storage::ipfs::client::IpfsClient;
+use crate::util::merkle::{self, Hash, Leaves, CHUNK_SIZE};
+
+
+/// chunks picked by a challenge unless asked otherwise
+pub const DEFAULT_CHALLENGES: usize = 8;
+/// most chunks a single challenge may ask for
+pub const MAX_CHALLENGES: usize = 64;
+
+/// One challenged chunk.
+#[derive(Serialize, Deserialize, Encode, Debug, Clone, Eq, PartialEq)]
+pub struct ChunkProof {
+    pub index: u64,
+    pub offset: u64,
+    pub length: u64,
+    /// hex blake2b-256 of the chunk as read from ipfs
+    pub hash: String,
+    /// hex sibling hashes from the leaf up to the root
+    pub path: Vec<String>,
+}
+
+/// The signed answer to a challenge.
+#[derive(Serialize, Deserialize, Encode, Debug, Clone, Eq, PartialEq)]
+pub struct Proof {
+    /// ipfs hash of the content
+    pub hash: String,
+    /// hex seed of the challenge
+    pub seed: String,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub leaves: u64,
+    pub chunks: Vec<ChunkProof>,
+    pub timestamp: i64,
+    /// ss58 address of the miner
+    pub miner: String,
+    /// hex sr25519 signature of the miner over `message`
+    #[codec(skip)]
+    pub signature: String,
+}
+
+fn decode_hash(value: &str) -> Result<Hash> {
+    let bytes = hex::decode(value.trim_start_matches("0x"))
+        .map_err(|_| MinerError::invalid(format!("`{}` is not a hex hash", value)))?;
+    if bytes.len() != 32 {
+        return Err(MinerError::invalid(format!("`{}` is not a 32 bytes hash", value)));
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&bytes);
+    Ok(hash)
+}
+
+impl Proof {
+    /// Answer a challenge by reading the chunks picked by `seed` from ipfs.
+    pub fn prove(ipfs_client: &IpfsClient, pair: &Pair, hash: &str, leaves: &Leaves, seed: &[u8], count: usize) -> Result<Proof> {
+        let mut chunks = vec![];
+        for index in merkle::challenge_indexes(seed, count, leaves.leaves.len()) {
+            let offset = index as u64 * CHUNK_SIZE;
+            let length = CHUNK_SIZE.min(leaves.size - offset.min(leaves.size));
+
+            let mut chunk = vec![];
+            ipfs_client.cat(hash, offset, Some(length))?.take(length).read_to_end(&mut chunk)?;
+
+            chunks.push(ChunkProof {
+                index: index as u64,
+                offset,
+                length: chunk.len() as u64,
+                hash: hex::encode(merkle::hash(&chunk)),
+                path: merkle::path(&leaves.leaves, index).iter().map(hex::encode).collect(),
+            });
+        }
+
+        let mut proof = Proof {
+            hash: hash.to_owned(),
+            seed: hex::encode(seed),
+            size: leaves.size,
+            chunk_size: CHUNK_SIZE,
+            leaves: leaves.leaves.len() as u64,
+            chunks,
+            timestamp: Utc::now().timestamp(),
+            miner: pair.public().to_ss58check(),
+            signature: String::new(),
+        };
+        proof.signature = hex::encode(pair.sign(&proof.message()).0.as_ref());
+        Ok(proof)
+    }
+
+    /// the bytes signed by the miner, the scale encoding of everything but the signature
+    pub fn message(&self) -> Vec<u8> {
+        self.encode()
+    }
+
+    /// Check that the proof answers the challenge of `count` chunks of `hash`
+    /// with `seed` the challenger sent, the signature, that the chunks are the
+    /// ones picked by the seed and that every chunk leads to `root`.
+    pub fn verify(&self, root: &str, miner: Option<&str>, hash: &str, seed: &str, count: usize) -> Result<()> {
+        if hash != self.hash {
+            return Err(MinerError::invalid(format!("proof answers a challenge of {}, not {}", self.hash, hash)));
+        }
+        let seed = hex::decode(seed.trim_start_matches("0x")).map_err(|_| MinerError::invalid("challenge seed is not hex encoded"))?;
+        if hex::decode(self.seed.trim_start_matches("0x")).ok() != Some(seed.clone()) {
+            return Err(MinerError::invalid("proof answers another seed than the one of the challenge"));
+        }
+        if count == 0 || count > MAX_CHALLENGES {
+            return Err(MinerError::invalid(format!("challenge count must be 1 to {}", MAX_CHALLENGES)));
+        }
+        if self.chunks.len() != count {
+            return Err(MinerError::invalid(format!("proof has {} chunks, the challenge asked for {}", self.chunks.len(), count)));
+        }
+
+        if let Some(miner) = miner {
+            if miner != self.miner {
+                return Err(MinerError::invalid(format!("proof is signed by {}, not {}", self.miner, miner)));
+            }
+        }
+        let signature = hex::decode(self.signature.trim_start_matches("0x"))
+            .map_err(|_| MinerError::invalid("signature is not hex encoded"))?;
+        if !Sr25519::verify(&signature, &self.message(), &self.miner) {
+            return Err(MinerError::invalid("invalid miner signature"));
+        }
+
+        if self.chunk_size == 0 || self.leaves != (self.size + self.chunk_size - 1).max(self.chunk_size) / self.chunk_size {
+            return Err(MinerError::invalid("proof size does not match its leaves"));
+        }
+        let indexes = merkle::challenge_indexes(&seed, self.chunks.len(), self.leaves as usize);
+        if indexes.iter().zip(self.chunks.iter()).any(|(index, chunk)| *index as u64 != chunk.index) {
+            return Err(MinerError::invalid("proof chunks are not the ones picked by the seed"));
+        }
+
+        let root = decode_hash(root)?;
+        for chunk in self.chunks.iter() {
+            let path = chunk.path.iter().map(|h| decode_hash(h)).collect::<Result<Vec<_>>>()?;
+            if merkle::root_from_path(&decode_hash(&chunk.hash)?, chunk.index as usize, &path) != root {
+                return Err(MinerError::invalid(format!("chunk {} does not lead to the merkle root", chunk.index)));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let (pair, _) = Pair::generate();
+        let data = vec![1u8; (CHUNK_SIZE * 3) as usize];
+        let leaves = merkle::leaves(&data[..]).unwrap();
+        let root = hex::encode(leaves.root());
+
+        let seed = b"challenge";
+        let chunks = merkle::challenge_indexes(seed, 2, leaves.leaves.len()).into_iter().map(|index| ChunkProof {
+            index: index as u64,
+            offset: index as u64 * CHUNK_SIZE,
+            length: CHUNK_SIZE,
+            hash: hex::encode(leaves.leaves[index]),
+            path: merkle::path(&leaves.leaves, index).iter().map(hex::encode).collect(),
+        }).collect();
+        let mut proof = Proof {
+            hash: "Qm".to_owned(),
+            seed: hex::encode(seed),
+            size: leaves.size,
+            chunk_size: CHUNK_SIZE,
+            leaves: leaves.leaves.len() as u64,
+            chunks,
+            timestamp: 0,
+            miner: pair.public().to_ss58check(),
+            signature: String::new(),
+        };
+        proof.signature = hex::encode(pair.sign(&proof.message()).0.as_ref());
+
+        let seed = hex::encode(seed);
+        assert!(proof.verify(&root, None, "Qm", &seed, 2).is_ok());
+        assert!(proof.verify(&root, Some("5Other"), "Qm", &seed, 2).is_err());
+        assert!(proof.verify(&hex::encode([0u8; 32]), None, "Qm", &seed, 2).is_err());
+
+        // the answer must be to the challenge that was sent
+        assert!(proof.verify(&root, None, "QmOther", &seed, 2).is_err());
+        assert!(proof.verify(&root, None, "Qm", &hex::encode(b"replayed"), 2).is_err());
+        assert!(proof.verify(&root, None, "Qm", &seed, 3).is_err());
+
+        let mut forged = proof.clone();
+        forged.chunks[0].hash = hex::encode(merkle::hash(b"forged"));
+        assert!(forged.verify(&root, None, "Qm", &seed, 2).is_err());
+
+        let mut empty = proof.clone();
+        empty.chunks.clear();
+        empty.signature = hex::encode(pair.sign(&empty.message()).0.as_ref());
+        assert!(empty.verify(&root, None, "Qm", &seed, 0).is_err());
+    }
+}
@@ -0,0 +1,328 @@
+//! Token bucket rate limits of the http api, per remote ip and per address.
+//!
+//! Every request takes a token from the bucket of its ip. A request finding an
+//! empty bucket is rerouted to `LIMITED_PATH`, which answers `429` with a
+//! `Retry-After`, so its handler never runs and its body is never read.
+//! Requests signed by the address in their path also take a token from the
+//! bucket of the address, once the signature is verified, so nobody else can
+//! drain the bucket of an address.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use chrono::Utc;
+use codec::{Encode, Decode};
+use kvdb::KeyValueDB;
+use kvdb_rocksdb::Database;
+use rocket::{Outcome, Request};
+use rocket::request::{self, FromRequest};
+use rocket::State;
+use rocket::fairing::AdHoc;
+use rocket::http::Method;
+use rocket::http::uri::Origin;
+
+use crate::constants::RATE_COL;
+use crate::settings::{Bucket as Limit, RateLimit};
+
+
+/// path the limited requests are rerouted to
+pub const LIMITED_PATH: &str = "/api/v0/rate-limited";
+/// milliseconds between two prunes and writes of the buckets
+const FLUSH_INTERVAL: i64 = 10_000;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Class {
+    /// data uploads
+    Upload,
+    /// other mutating requests
+    Write,
+    /// storage challenges, which read the challenged chunks from ipfs
+    Challenge,
+    Read,
+}
+
+impl Class {
+    fn name(self) -> &'static str {
+        match self {
+            Class::Upload => "upload",
+            Class::Write => "write",
+            Class::Challenge => "challenge",
+            Class::Read => "read",
+        }
+    }
+}
+
+/// Class of an api request and the address in its path, `None` for unlimited requests.
+pub fn classify<'a>(method: Method, path: &'a str) -> Option<(Class, Option<&'a str>)> {
+    let segments: Vec<&str> = path.trim_start_matches("/api/v0/").split('/').filter(|s| !s.is_empty()).collect();
+    if !path.starts_with("/api/v0/") || segments.is_empty() {
+        return None;
+    }
+
+    let class = match (method, segments[0]) {
+        // load balancer checks are never limited
+        (_, "status") | (_, "rate-limited") => return None,
+        (_, "challenge") => Class::Challenge,
+        (Method::Get, _) | (Method::Head, _) | (Method::Options, _) => Class::Read,
+        (Method::Post, "order") if segments.len() == 2 => Class::Upload,
+        (_, "upload") => Class::Upload,
+        _ => Class::Write,
+    };
+
+    let address = match segments[0] {
+        "order" if segments.get(1) == Some(&"verify") => segments.get(2),
+        "order" | "upload" | "usage" => segments.get(1),
+        _ => None,
+    };
+    Some((class, address.copied()))
+}
+
+/// A token bucket, `tokens` as of `updated` in unix milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    pub updated: i64,
+}
+
+impl TokenBucket {
+    /// scale has no floats, the tokens are stored by their bits
+    fn to_bytes(&self) -> Vec<u8> {
+        (self.tokens.to_bits(), self.updated).encode()
+    }
+
+    fn from_bytes(value: &[u8]) -> Option<Self> {
+        let (tokens, updated) = <(u64, i64)>::decode(&mut &value[..]).ok()?;
+        Some(TokenBucket { tokens: f64::from_bits(tokens), updated })
+    }
+
+    fn full(limit: &Limit, now: i64) -> Self {
+        TokenBucket { tokens: limit.capacity as f64, updated: now }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: i64) {
+        let elapsed = (now - self.updated).max(0) as f64 / 60_000.0;
+        self.tokens = (self.tokens + elapsed * limit.per_minute as f64).min(limit.capacity as f64);
+        self.updated = now;
+    }
+
+    /// seconds until a token is available, 0 when there is one
+    fn wait(&self, limit: &Limit) -> u64 {
+        if self.tokens >= 1.0 {
+            return 0;
+        }
+        if limit.per_minute == 0 {
+            return 60;
+        }
+        ((1.0 - self.tokens) * 60.0 / limit.per_minute as f64).ceil() as u64
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<String, TokenBucket>,
+    flushed: i64,
+}
+
+impl Buckets {
+    /// take a token from every bucket of `keys`, or from none of them.
+    /// Returns the seconds to wait when one of them is empty
+    fn take(&mut self, keys: &[String], limit: &Limit, now: i64) -> Option<u64> {
+        let mut wait = 0;
+        for key in keys {
+            let bucket = self.buckets.entry(key.clone()).or_insert_with(|| TokenBucket::full(limit, now));
+            bucket.refill(limit, now);
+            wait = wait.max(bucket.wait(limit));
+        }
+        if wait > 0 {
+            return Some(wait);
+        }
+        for key in keys {
+            if let Some(bucket) = self.buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        None
+    }
+
+    /// forget the buckets refilled to their capacity, returning their keys
+    fn prune(&mut self, settings: &RateLimit, now: i64) -> Vec<String> {
+        let mut pruned = vec![];
+        self.buckets.retain(|key, bucket| {
+            let limit = match key.split(':').next() {
+                Some("upload") => &settings.upload,
+                Some("write") => &settings.write,
+                Some("challenge") => &settings.challenge,
+                _ => &settings.read,
+            };
+            bucket.refill(limit, now);
+            let full = bucket.tokens >= limit.capacity as f64;
+            if full {
+                pruned.push(key.clone());
+            }
+            !full
+        });
+        self.flushed = now;
+        pruned
+    }
+}
+
+/// Seconds a limited request has to wait, kept for the `rate-limited` route.
+pub struct Limited(pub u64);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Limited {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(Limited(request.local_cache(|| Limited(0)).0))
+    }
+}
+
+pub struct RateLimiter {
+    settings: RateLimit,
+    buckets: Mutex<Buckets>,
+    /// where the buckets survive restarts, when persisted
+    db: Option<Arc<Database>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimit, db: Option<Arc<Database>>) -> Self {
+        let mut buckets = Buckets::default();
+
+        if let Some(ref db) = db {
+            for (key, value) in db.iter(RATE_COL) {
+                if let (Ok(key), Some(bucket)) = (String::from_utf8(key.to_vec()), TokenBucket::from_bytes(&value)) {
+                    buckets.buckets.insert(key, bucket);
+                }
+            }
+        }
+        buckets.flushed = Utc::now().timestamp_millis();
+
+        RateLimiter { settings, buckets: Mutex::new(buckets), db }
+    }
+
+    fn limit(&self, class: Class) -> &Limit {
+        match class {
+            Class::Upload => &self.settings.upload,
+            Class::Write => &self.settings.write,
+            Class::Challenge => &self.settings.challenge,
+            Class::Read => &self.settings.read,
+        }
+    }
+
+    /// Seconds the request has to wait for its ip bucket, `None` when it may pass.
+    pub fn check(&self, method: Method, path: &str, ip: Option<String>) -> Option<u64> {
+        let ip = ip?;
+        let (class, _) = classify(method, path)?;
+        self.take(class, format!("{}:ip:{}", class.name(), ip))
+    }
+
+    /// Seconds a request signed by `address` has to wait for the bucket of
+    /// the address, `None` when it may pass. Only called once the signature
+    /// is verified.
+    pub fn check_address(&self, method: Method, path: &str, address: &str) -> Option<u64> {
+        let (class, _) = classify(method, path)?;
+        self.take(class, format!("{}:address:{}", class.name(), address))
+    }
+
+    fn take(&self, class: Class, key: String) -> Option<u64> {
+        let limit = self.limit(class);
+        if limit.capacity == 0 {
+            return None;
+        }
+
+        let now = Utc::now().timestamp_millis();
+        let mut buckets = self.buckets.lock().unwrap();
+        let wait = buckets.take(&[key], limit, now);
+
+        if now - buckets.flushed > FLUSH_INTERVAL {
+            self.flush(&mut buckets, now);
+        }
+        wait
+    }
+
+    /// forget the refilled buckets and, when persisted, write the others to the database
+    fn flush(&self, buckets: &mut Buckets, now: i64) {
+        let pruned = buckets.prune(&self.settings, now);
+
+        let db = match self.db {
+            Some(ref db) => db,
+            None => return,
+        };
+
+        let mut batch = db.transaction();
+        for key in pruned {
+            batch.delete(RATE_COL, key.as_bytes());
+        }
+        for (key, bucket) in buckets.buckets.iter() {
+            batch.put(RATE_COL, key.as_bytes(), &bucket.to_bytes());
+        }
+        if let Err(e) = db.write(batch) {
+            log::warn!("rate limits could not be saved: {}", e);
+        }
+    }
+}
+
+/// Reroute the requests limited by the managed `RateLimiter`.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_request("Rate limiter", |request, _| {
+        let limiter = match request.guard::<State<RateLimiter>>().succeeded() {
+            Some(limiter) => limiter,
+            None => return,
+        };
+        let ip = request.client_ip().map(|ip| ip.to_string());
+        if let Some(wait) = limiter.check(request.method(), request.uri().path(), ip) {
+            log::warn!("{} {} rate limited for {}s", request.method(), request.uri(), wait);
+            request.local_cache(|| Limited(wait));
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(LIMITED_PATH).expect("valid rate limited path"));
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(Some((Class::Upload, Some("5A"))), classify(Method::Post, "/api/v0/order/5A"));
+        assert_eq!(Some((Class::Write, Some("5A"))), classify(Method::Post, "/api/v0/order/5A/Qm/extend"));
+        assert_eq!(Some((Class::Upload, Some("5A"))), classify(Method::Patch, "/api/v0/upload/5A/id"));
+        assert_eq!(Some((Class::Write, Some("5A"))), classify(Method::Post, "/api/v0/order/verify/5A/Qm"));
+        assert_eq!(Some((Class::Read, None)), classify(Method::Get, "/api/v0/data/Qm"));
+        assert_eq!(Some((Class::Challenge, None)), classify(Method::Get, "/api/v0/challenge/Qm"));
+        assert_eq!(None, classify(Method::Get, "/api/v0/status"));
+        assert_eq!(None, classify(Method::Get, "/favicon.ico"));
+    }
+
+    #[test]
+    fn test_take() {
+        let limit = Limit { capacity: 2, per_minute: 60 };
+        let mut buckets = Buckets::default();
+        let keys = vec!["upload:ip:1".to_owned(), "upload:address:5A".to_owned()];
+
+        assert_eq!(None, buckets.take(&keys, &limit, 0));
+        assert_eq!(None, buckets.take(&keys, &limit, 0));
+        assert_eq!(Some(1), buckets.take(&keys, &limit, 0));
+
+        // one token per second comes back
+        assert_eq!(None, buckets.take(&keys, &limit, 1_000));
+        // another ip of the same address is limited by the address bucket
+        assert_eq!(Some(1), buckets.take(&["upload:ip:2".to_owned(), "upload:address:5A".to_owned()], &limit, 1_000));
+        assert_eq!(2.0, buckets.buckets["upload:ip:2"].tokens);
+    }
+
+    #[test]
+    fn test_prune() {
+        let settings = RateLimit::default();
+        let mut buckets = Buckets::default();
+        let limit = &settings.upload;
+
+        assert_eq!(None, buckets.take(&["upload:ip:1".to_owned()], limit, 0));
+        assert_eq!(None, buckets.take(&["upload:ip:2".to_owned()], limit, 59_000));
+
+        // the bucket of the first ip is full again, the second one still misses its token
+        assert_eq!(vec!["upload:ip:1".to_owned()], buckets.prune(&settings, 60_000));
+        assert!(buckets.buckets.contains_key("upload:ip:2"));
+        assert_eq!(60_000, buckets.flushed);
+    }
+}
@@ -0,0 +1,218 @@
+//! Reconciliation of the local orders with the chain.
+//!
+//! The database, the pins of the ipfs node and the chain orders assigned to
+//! the miner are compared and every drift is reported. The optional fixes
+//! re-pin missing content, unpin content no order holds and relink the orders
+//! the database and the chain disagree about.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use codec::Encode;
+use futures::executor;
+use kvdb::KeyValueDB;
+use kvdb_rocksdb::Database;
+use sp_core::{sr25519::Pair, Pair as PairT};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use substrate_subxt::Client;
+
+use crate::chain::{self, IpseRuntime, ProfileDiff};
+use crate::constants::CHAIN_COL;
+use crate::error::Result;
+use crate::settings::Settings;
+use crate::storage::ipfs::client::IpfsClient;
+use crate::storage::kv::order;
+
+
+/// An order as known by the database or by the chain.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Entry {
+    pub address: String,
+    pub hash: String,
+    pub order_id: Option<u64>,
+}
+
+/// Which fixes a reconciliation applies.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Fix {
+    /// pin the content of orders that lost it
+    pub repin: bool,
+    /// unpin the content no order holds, only for a node that pins nothing
+    /// but the content of this miner
+    pub unpin: bool,
+    /// record the ids of unlinked orders and drop the ids of orphaned ones
+    pub mark: bool,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    /// ss58 address of the miner
+    pub miner: String,
+    pub registered: bool,
+    /// registration fields differing from the settings
+    pub miner_diffs: Vec<ProfileDiff>,
+    pub chain_orders: usize,
+    pub local_orders: usize,
+    /// chain orders assigned to the miner whose content is not pinned
+    pub missing: Vec<Entry>,
+    /// local orders whose content is not pinned
+    pub unpinned: Vec<Entry>,
+    /// local orders with an id the chain does not assign to the miner
+    pub orphaned: Vec<Entry>,
+    /// local orders matching a chain order whose id was never recorded
+    pub unlinked: Vec<Entry>,
+    /// pinned content no order holds
+    pub stray_pins: Vec<String>,
+    pub fixed: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl Report {
+    /// whether the database, the node and the chain agree
+    pub fn is_clean(&self) -> bool {
+        self.registered && self.miner_diffs.is_empty() && self.missing.is_empty() && self.unpinned.is_empty()
+            && self.orphaned.is_empty() && self.unlinked.is_empty() && self.stray_pins.is_empty()
+    }
+}
+
+/// Compare the local orders with the chain orders assigned to the miner and the pins.
+pub fn compare(local: &[Entry], chain: &[Entry], pins: &HashSet<String>) -> Report {
+    let by_id: HashMap<u64, &Entry> = chain.iter().filter_map(|c| c.order_id.map(|id| (id, c))).collect();
+    let by_order: HashMap<(&str, &str), u64> = chain.iter()
+        .filter_map(|c| c.order_id.map(|id| ((c.address.as_str(), c.hash.as_str()), id)))
+        .collect();
+
+    let mut report = Report {
+        chain_orders: chain.len(),
+        local_orders: local.len(),
+        ..Report::default()
+    };
+
+    report.missing = chain.iter().filter(|c| !pins.contains(&c.hash)).cloned().collect();
+    for entry in local {
+        if !pins.contains(&entry.hash) {
+            report.unpinned.push(entry.clone());
+        }
+        match entry.order_id {
+            Some(id) if by_id.get(&id).map(|c| c.hash != entry.hash).unwrap_or(true) => report.orphaned.push(entry.clone()),
+            Some(_) => {}
+            None => {
+                if let Some(id) = by_order.get(&(entry.address.as_str(), entry.hash.as_str())) {
+                    report.unlinked.push(Entry { order_id: Some(*id), ..entry.clone() });
+                }
+            }
+        }
+    }
+
+    let held: HashSet<&str> = local.iter().chain(chain.iter()).map(|e| e.hash.as_str()).collect();
+    report.stray_pins = pins.iter().filter(|p| !held.contains(p.as_str())).cloned().collect();
+    report.stray_pins.sort();
+    report
+}
+
+fn local_entries(db: &Database) -> Result<Vec<Entry>> {
+    // an order uploaded more than once is a single entry
+    let orders: BTreeMap<(String, String), ()> = order::orders(db, b"")
+        .map(|o| ((o.address, o.hash), ()))
+        .collect();
+    orders.into_iter()
+        .map(|((address, hash), _)| {
+            let order_id = order::get_order_id(db, &address, &hash)?;
+            Ok(Entry { address, hash, order_id })
+        })
+        .collect()
+}
+
+/// Compare the database and the pins with the chain, then apply `fix`.
+pub fn reconcile(
+    db: &Database,
+    ipfs_client: &IpfsClient,
+    pair: &Pair,
+    sub_client: &Client<IpseRuntime>,
+    settings: &Settings,
+    fix: Fix,
+) -> Result<Report> {
+    let account = AccountId32::from(pair.public());
+    let miner = executor::block_on(chain::miner(sub_client, account.clone()))?;
+    let chain: Vec<_> = executor::block_on(chain::orders(sub_client))?.into_iter().enumerate()
+        .filter(|(_, o)| o.orders.iter().any(|m| m.miner == account))
+        .map(|(order_id, o)| Entry {
+            address: o.user.to_ss58check(),
+            hash: String::from_utf8_lossy(&o.key).into_owned(),
+            order_id: Some(order_id as u64),
+        })
+        .collect();
+    let local = local_entries(db)?;
+    let pins: HashSet<String> = ipfs_client.pins()?.into_iter().collect();
+
+    let mut report = compare(&local, &chain, &pins);
+    report.miner = account.to_ss58check();
+    report.registered = miner.is_some();
+    if let Some(miner) = miner {
+        report.miner_diffs = chain::profile_diff(&miner, settings);
+    }
+
+    if fix.repin {
+        let hashes: HashSet<String> = report.missing.iter().chain(report.unpinned.iter()).map(|e| e.hash.clone()).collect();
+        for hash in hashes {
+            match ipfs_client.pin_add(&hash) {
+                Ok(_) => report.fixed.push(format!("pinned {}", hash)),
+                Err(e) => report.errors.push(format!("pin {}: {}", hash, e)),
+            }
+        }
+    }
+    if fix.unpin {
+        for hash in report.stray_pins.iter() {
+            match ipfs_client.pin_rm(hash) {
+                Ok(_) => report.fixed.push(format!("unpinned {}", hash)),
+                Err(e) => report.errors.push(format!("unpin {}: {}", hash, e)),
+            }
+        }
+    }
+    if fix.mark {
+        let mut batch = db.transaction();
+        for entry in report.unlinked.iter() {
+            let order_id = entry.order_id.unwrap_or_default();
+            batch.put(CHAIN_COL, &order::info_key(&entry.address, &entry.hash), &order_id.encode());
+            report.fixed.push(format!("linked {} of {} to order {}", entry.hash, entry.address, order_id));
+        }
+        // the recorded id is not a chain order of this content, the local
+        // order forgets it and is linked again once its own order shows up
+        for entry in report.orphaned.iter() {
+            batch.delete(CHAIN_COL, &order::info_key(&entry.address, &entry.hash));
+            report.fixed.push(format!("unlinked {} of {} from order {}", entry.hash, entry.address, entry.order_id.unwrap_or_default()));
+        }
+        db.write(batch)?;
+    }
+
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(address: &str, hash: &str, order_id: Option<u64>) -> Entry {
+        Entry { address: address.to_owned(), hash: hash.to_owned(), order_id }
+    }
+
+    #[test]
+    fn test_compare() {
+        let local = vec![
+            entry("5A", "QmA", Some(0)),
+            entry("5A", "QmB", None),
+            entry("5B", "QmC", Some(7)),
+        ];
+        let chain = vec![
+            entry("5A", "QmA", Some(0)),
+            entry("5A", "QmB", Some(1)),
+            entry("5C", "QmD", Some(2)),
+        ];
+        let pins: HashSet<String> = vec!["QmA", "QmB", "QmE"].into_iter().map(String::from).collect();
+
+        let report = compare(&local, &chain, &pins);
+        assert_eq!(vec![entry("5C", "QmD", Some(2))], report.missing);
+        assert_eq!(vec![entry("5B", "QmC", Some(7))], report.unpinned);
+        assert_eq!(vec![entry("5B", "QmC", Some(7))], report.orphaned);
+        assert_eq!(vec![entry("5A", "QmB", Some(1))], report.unlinked);
+        assert_eq!(vec!["QmE".to_owned()], report.stray_pins);
+    }
+}
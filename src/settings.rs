@@ -12,6 +12,9 @@ use crate::storage::kv::rocksdb::KVDatabase;
 use crate::constants::META_COL;
 use crate::error::{Result, MinerError};
 use crate::storage::ipfs::client::IpfsClient;
+use crate::storage::upload::Uploads;
+use sp_core::{sr25519::Pair, Pair as PairT};
+use blake2_rfc::blake2b::blake2b;
 
 
 #[derive(Debug, Deserialize, Clone)]
@@ -24,11 +27,44 @@ pub struct Miner {
     pub income_address: String,
     pub capacity: u64,
     pub unit_price: u64,
+    /// most days an order may be stored or extended by at once
+    #[serde(default = "default_max_days")]
+    pub max_days: u64,
+}
+
+fn default_max_days() -> u64 {
+    3650
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Chain {
     pub url: String,
+    /// nodes tried in order when `url` cannot be reached
+    #[serde(default)]
+    pub fallback_urls: Vec<String>,
+    /// decimals of the chain `Balance`
+    #[serde(default = "default_decimals")]
+    pub decimals: u32,
+    /// symbol of one `10^decimals` plancks
+    #[serde(default = "default_symbol")]
+    pub symbol: String,
+}
+
+fn default_decimals() -> u32 {
+    14
+}
+
+fn default_symbol() -> String {
+    "POST".to_owned()
+}
+
+impl Chain {
+    /// every node url, the preferred one first
+    pub fn urls(&self) -> Vec<String> {
+        let mut urls = vec![self.url.clone()];
+        urls.extend(self.fallback_urls.iter().filter(|url| **url != self.url).cloned());
+        urls
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -39,6 +75,48 @@ pub struct Search {
 #[derive(Debug, Deserialize, Clone)]
 pub struct Serve {
     pub secret_key: String,
+    /// token of the admin api, derived from `secret_key` when empty
+    #[serde(default)]
+    pub admin_key: String,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+}
+
+/// A token bucket, `capacity` requests at once refilled by `per_minute`, 0 capacity is unlimited.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Bucket {
+    pub capacity: u32,
+    pub per_minute: u32,
+}
+
+/// Limits of the http api, applied per remote ip and per address.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimit {
+    /// keep the buckets in the database across restarts
+    #[serde(default)]
+    pub persist: bool,
+    pub upload: Bucket,
+    pub write: Bucket,
+    /// storage challenges, cheap to send but reading chunks from ipfs
+    #[serde(default = "default_challenge")]
+    pub challenge: Bucket,
+    pub read: Bucket,
+}
+
+fn default_challenge() -> Bucket {
+    Bucket { capacity: 10, per_minute: 10 }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            persist: false,
+            upload: Bucket { capacity: 10, per_minute: 10 },
+            write: Bucket { capacity: 60, per_minute: 60 },
+            challenge: default_challenge(),
+            read: Bucket { capacity: 600, per_minute: 600 },
+        }
+    }
 }
 
 
@@ -46,6 +124,13 @@ pub struct Serve {
 pub struct Data {
     pub db: String,
     pub keystore: String,
+    /// directory of resumable upload sessions
+    #[serde(default = "default_uploads")]
+    pub uploads: String,
+}
+
+fn default_uploads() -> String {
+    "uploads".to_owned()
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -54,6 +139,66 @@ pub struct Ipfs {
     pub local: bool,
 }
 
+/// storage limit of one address, 0 means unlimited
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Limit {
+    #[serde(default)]
+    pub max_bytes: u64,
+    #[serde(default)]
+    pub max_objects: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Quota {
+    #[serde(default)]
+    pub max_bytes: u64,
+    #[serde(default)]
+    pub max_objects: u64,
+    /// overrides for single addresses
+    #[serde(default)]
+    pub addresses: HashMap<String, Limit>,
+}
+
+impl Quota {
+    pub fn limit(&self, address: &str) -> Limit {
+        match self.addresses.get(address) {
+            Some(limit) => limit.clone(),
+            None => Limit { max_bytes: self.max_bytes, max_objects: self.max_objects },
+        }
+    }
+}
+
+/// Receivers of order events, see `webhook`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Webhooks {
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// hours before the expiry an order is announced as expiring
+    #[serde(default = "default_expiring_hours")]
+    pub expiring_hours: u64,
+    /// failed deliveries are dead-lettered after this many attempts
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_expiring_hours() -> u64 {
+    24
+}
+
+fn default_max_attempts() -> u32 {
+    10
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Webhooks {
+            urls: vec![],
+            expiring_hours: default_expiring_hours(),
+            max_attempts: default_max_attempts(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     pub miner: Miner,
@@ -62,6 +207,10 @@ pub struct Settings {
     pub ipfs: Ipfs,
     pub search: Search,
     pub serve: Serve,
+    #[serde(default)]
+    pub quota: Quota,
+    #[serde(default)]
+    pub webhooks: Webhooks,
 }
 
 
@@ -88,14 +237,31 @@ impl Settings {
 }
 
 
-pub fn sub_client(settings: &Settings) -> Result<Client<IpseRuntime>> {
-    let chain_url = settings.chain.url.clone();
-
+/// connect to the node at `url`
+pub fn chain_client(url: &str) -> Result<Client<IpseRuntime>> {
     executor::block_on(
         ClientBuilder::<IpseRuntime>::new()
-            .set_url(chain_url)
+            .set_url(url)
             .build()
-    ).map_err(|_| MinerError::msg("ipfs server connect error"))
+    ).map_err(|e| MinerError::upstream(format!("chain node {} connect error: {}", url, e)))
+}
+
+/// connect to the first reachable node of `chain.url` and `chain.fallback_urls`
+pub fn sub_client(settings: &Settings) -> Result<Client<IpseRuntime>> {
+    let mut errors = vec![];
+    for url in settings.chain.urls() {
+        match chain_client(&url) {
+            Ok(client) => return Ok(client),
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    Err(MinerError::upstream(errors.join(", ")))
+}
+
+/// signing pair of the miner from `miner.secret_seed`
+pub fn miner_pair(settings: &Settings) -> Result<Pair> {
+    let seed = settings.miner.secret_seed.as_str();
+    Ok(Pair::from_seed_slice(&hex::decode(&seed[..])?)?)
 }
 
 pub fn kv_database(settings: &Settings) -> Result<KVDatabase> {
@@ -112,4 +278,16 @@ pub fn kv_database(settings: &Settings) -> Result<KVDatabase> {
 
 pub fn ipfs_client(settings: &Settings) -> Result<IpfsClient> {
     Ok(IpfsClient::new(settings.ipfs.uri.as_str()))
+}
+
+/// bearer token of the admin api
+pub fn admin_token(settings: &Settings) -> String {
+    if !settings.serve.admin_key.is_empty() {
+        return settings.serve.admin_key.clone();
+    }
+    hex::encode(blake2b(32, b"ipse-admin", settings.serve.secret_key.as_bytes()).as_bytes())
+}
+
+pub fn uploads(settings: &Settings) -> Result<Uploads> {
+    Ok(Uploads::new(PathBuf::from(&settings.data.uploads)))
 }
\ No newline at end of file
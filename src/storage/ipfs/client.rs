@@ -1,14 +1,24 @@
 use serde::Serialize;
+use serde::de::DeserializeOwned;
 use codec::{Encode, Decode};
+use reqwest::blocking::{Body, Client, Response, multipart};
+use reqwest::header::CONTENT_TYPE;
 
 use nix::sys::stat::stat;
+use nix::unistd::{getuid, getgid};
+use chrono::Local;
 use std::path::Path;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
 
-use crate::error::{MinerError, Result};
+use crate::error::{MinerError, MinerErrorKind, Result};
+use crate::util::data::HashReader;
+use crate::storage::ipfs::model::{ApiError, AddResponse, PinResponse, PinLsResponse, ObjectStat, FilesStat, RepoGcResponse, RepoStat};
 
 #[derive(Clone)]
 pub struct IpfsClient {
     uri: String,
+    client: Client,
 }
 
 
@@ -45,12 +55,55 @@ pub struct Stat {
     pub st_ctime: i64,
     // time of last file status change
     pub st_ctime_nsec: i64,
+    pub digest: String,
+    // blake2b-256 of the uploaded bytes, empty for orders stored before it was recorded
+}
+
+
+impl Stat {
+    /// Decode an order record. Records stored before `digest` was added end
+    /// right before it and are read with an empty digest.
+    pub fn from_record(value: &[u8]) -> std::result::Result<Self, codec::Error> {
+        Stat::decode(&mut &value[..]).or_else(|e| {
+            // an empty string is encoded as its zero length
+            let mut legacy = value.to_vec();
+            legacy.push(0);
+            Stat::decode(&mut &legacy[..]).map_err(|_| e)
+        })
+    }
+
+    /// stat of data that never touched the local disk
+    pub fn from_stream(hash: String, digest: String, size: u64) -> Self {
+        let now = Local::now();
+        let blksize = 4096;
+
+        Stat {
+            hash,
+            digest,
+            st_dev: 0,
+            st_ino: 0,
+            st_nlink: 1,
+            st_mode: 0o100644,
+            st_uid: getuid().as_raw(),
+            st_gid: getgid().as_raw(),
+            st_rdev: 0,
+            st_size: size as i64,
+            st_blksize: blksize,
+            st_blocks: (size as i64 + 511) / 512,
+            st_atime: now.timestamp(),
+            st_atime_nsec: now.timestamp_subsec_nanos() as i64,
+            st_mtime: now.timestamp(),
+            st_mtime_nsec: now.timestamp_subsec_nanos() as i64,
+            st_ctime: now.timestamp(),
+            st_ctime_nsec: now.timestamp_subsec_nanos() as i64,
+        }
+    }
 }
 
 
 impl Default for IpfsClient {
     fn default() -> Self {
-        Self { uri: "http://127.0.0.1:5001".parse().unwrap() }
+        Self::new("http://127.0.0.1:5001")
     }
 }
 
@@ -59,8 +112,15 @@ impl IpfsClient {
     /// ipfs http api,
     /// https://docs.ipfs.io/reference/http/api/
     pub fn new(uri: &str) -> IpfsClient {
+        // uploads can take longer than the default request timeout
+        let client = Client::builder()
+            .timeout(None)
+            .build()
+            .unwrap_or_else(|_| Client::new());
+
         IpfsClient {
-            uri: uri.to_string(),
+            uri: uri.trim_end_matches('/').to_string(),
+            client,
         }
     }
 
@@ -68,24 +128,47 @@ impl IpfsClient {
         format!("{}", self.uri)
     }
 
-    pub fn add(&self, data: &str) -> Result<Stat> {
-        let (code, stdout, stderr) = sh!("ipfs add  {}", data);
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/api/v0/{}", self.uri, path)
+    }
 
+    /// turn a non 2xx response into the error body returned by the ipfs api
+    fn check(response: Response) -> Result<Response> {
+        if response.status().is_success() {
+            return Ok(response);
+        }
 
-        if &stderr == "" {
-            return Err(MinerError::msg("serve add file error"));
+        let status = response.status();
+        let body = response.text()?;
+        match serde_json::from_str::<ApiError>(&body) {
+            Ok(e) => Err(e.into()),
+            Err(_) => Err(MinerError::upstream(format!("ipfs api responded {}: {}", status, body))),
         }
+    }
+
+    fn post(&self, path: &str, query: &[(&str, &str)]) -> Result<Response> {
+        let response = self.client.post(&self.endpoint(path))
+            .query(query)
+            .send()?;
+        Self::check(response)
+    }
+
+    fn post_json<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T> {
+        Ok(self.post(path, query)?.json::<T>()?)
+    }
 
+    /// add a local file and pin it
+    pub fn add(&self, data: &str) -> Result<Stat> {
         let filename = Path::new(data);
 
-        let mut iter = stdout.split_whitespace();
-        iter.next();
-        let file_hash = iter.next().unwrap();
+        let stat_result = stat(filename).map_err(|e| MinerError::msg(e))?;
 
-        let stat_result = stat(filename).unwrap();
+        let name = filename.file_name().and_then(|n| n.to_str()).unwrap_or("file");
+        let added = self.add_stream(name, File::open(filename)?)?;
 
         Ok(Stat {
-            hash: file_hash.to_string(),
+            hash: added.hash,
+            digest: added.digest,
             st_dev: stat_result.st_dev,
             st_ino: stat_result.st_ino,
             st_nlink: stat_result.st_nlink,
@@ -105,14 +188,210 @@ impl IpfsClient {
         })
     }
 
-    pub fn delete(&self, hash: &str) -> (i32, String, String) {
-        sh!("ipfs pin rm  {} && ipfs repo gc", hash)
+    /// add a stream without buffering it on disk, hashing and counting it on the way
+    pub fn add_stream<R: Read + Send + 'static>(&self, name: &str, data: R) -> Result<Stat> {
+        let reader = HashReader::new(data);
+        let summary = reader.summary();
+
+        let part = multipart::Part::reader(reader).file_name(name.to_string());
+        let form = multipart::Form::new().part("file", part);
+
+        let response = self.client.post(&self.endpoint("add"))
+            .query(&[("pin", "true")])
+            .multipart(form)
+            .send()?;
+        let added = Self::check(response)?.json::<AddResponse>()?;
+
+        Ok(Stat::from_stream(added.hash, summary.digest(), summary.size()))
+    }
+
+    /// add every file of a `multipart/form-data` body wrapped in a directory,
+    /// the body is passed through as it is so files are never buffered.
+    /// Returns the stat of the directory, sized by its files rather than by the
+    /// multipart framing, and the added files.
+    pub fn add_multipart<R: Read + Send + 'static>(&self, content_type: &str, body: R) -> Result<(Stat, Vec<AddResponse>)> {
+        let reader = HashReader::new(body);
+        let summary = reader.summary();
+
+        let response = self.client.post(&self.endpoint("add"))
+            .query(&[("pin", "true"), ("wrap-with-directory", "true")])
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::new(reader))
+            .send()?;
+
+        let mut added = vec![];
+        for line in BufReader::new(Self::check(response)?).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                added.push(serde_json::from_str::<AddResponse>(&line)?);
+            }
+        }
+
+        // the wrapping directory is reported last, without a name
+        let root = match added.iter().rposition(|a| a.name.is_empty()) {
+            Some(i) => added.remove(i),
+            None => return Err(MinerError::upstream("ipfs add returned no directory")),
+        };
+
+        let size = added.iter()
+            .filter(|a| !a.name.contains('/'))
+            .map(|a| a.size.parse::<u64>().unwrap_or_default())
+            .sum();
+        let mut stat = Stat::from_stream(root.hash, summary.digest(), size);
+        stat.st_mode = 0o040755;
+        Ok((stat, added))
+    }
+
+    /// unpin the hash and collect the freed blocks
+    pub fn delete(&self, hash: &str) -> Result<Vec<String>> {
+        let unpinned = self.pin_rm(hash)?;
+        self.repo_gc()?;
+        Ok(unpinned)
+    }
+
+    pub fn pin_add(&self, hash: &str) -> Result<Vec<String>> {
+        Ok(self.post_json::<PinResponse>("pin/add", &[("arg", hash)])?.pins)
+    }
+
+    pub fn pin_rm(&self, hash: &str) -> Result<Vec<String>> {
+        Ok(self.post_json::<PinResponse>("pin/rm", &[("arg", hash)])?.pins)
+    }
+
+    /// whether the hash is pinned recursively on the node
+    pub fn pin_ls(&self, hash: &str) -> Result<bool> {
+        let response = self.client.post(&self.endpoint("pin/ls"))
+            .query(&[("arg", hash), ("type", "recursive")])
+            .send()?;
+
+        // the api answers with an error when the hash is not pinned
+        match Self::check(response) {
+            Ok(response) => Ok(response.json::<PinLsResponse>()?.keys.contains_key(hash)),
+            Err(MinerError { kind: MinerErrorKind::IpfsApi(ref e), .. }) if e.message.contains("not pinned") => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// hashes pinned recursively on the node
+    pub fn pins(&self) -> Result<Vec<String>> {
+        let pins = self.post_json::<PinLsResponse>("pin/ls", &[("type", "recursive")])?;
+        Ok(pins.keys.into_iter().map(|(hash, _)| hash).collect())
+    }
+
+    /// read `length` bytes (or everything when `None`) from `offset` of the content
+    pub fn cat(&self, hash: &str, offset: u64, length: Option<u64>) -> Result<Response> {
+        let offset = offset.to_string();
+        let length = length.map(|l| l.to_string());
+
+        let mut query = vec![("arg", hash), ("offset", offset.as_str())];
+        if let Some(ref length) = length {
+            query.push(("length", length.as_str()));
+        }
+        self.post("cat", &query)
+    }
+
+    pub fn object_stat(&self, hash: &str) -> Result<ObjectStat> {
+        self.post_json::<ObjectStat>("object/stat", &[("arg", hash)])
+    }
+
+    /// unixfs stat of the content, `size` is the size of the file itself
+    pub fn files_stat(&self, hash: &str) -> Result<FilesStat> {
+        let path = format!("/ipfs/{}", hash);
+        self.post_json::<FilesStat>("files/stat", &[("arg", path.as_str())])
+    }
+
+    /// run the garbage collector, returning the removed cids
+    pub fn repo_gc(&self) -> Result<Vec<String>> {
+        let response = self.post("repo/gc", &[])?;
+
+        let mut removed = vec![];
+        for line in BufReader::new(response).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let res = serde_json::from_str::<RepoGcResponse>(&line)?;
+            if let Some(e) = res.error {
+                return Err(MinerError::upstream(e));
+            }
+            if let Some(key) = res.key {
+                removed.push(key.cid);
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn repo_stat(&self) -> Result<RepoStat> {
+        self.post_json::<RepoStat>("repo/stat", &[("size-only", "false")])
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::storage::ipfs::client::IpfsClient;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use blake2_rfc::blake2b::blake2b;
+    use codec::Encode;
+
+    use crate::storage::ipfs::client::{IpfsClient, Stat};
+    use crate::error::MinerErrorKind;
+
+    /// answer a single request with the given status line and json body
+    fn mock_server(status: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let (mut chunked, mut length) = (false, 0);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                let header = line.to_ascii_lowercase();
+                if header.starts_with("transfer-encoding:") && header.contains("chunked") {
+                    chunked = true;
+                }
+                if let Some(value) = header.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+                line.clear();
+            }
+
+            // drain the request body, closing with unread data would reset the connection
+            if chunked {
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    let size = usize::from_str_radix(line.trim(), 16).unwrap_or(0);
+                    reader.read_exact(&mut vec![0; size + 2]).unwrap();
+                    if size == 0 {
+                        break;
+                    }
+                }
+            } else {
+                reader.read_exact(&mut vec![0; length]).unwrap();
+            }
+            write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   status, body.len(), body).unwrap();
+        });
+
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_legacy_stat() {
+        let stat = Stat::from_stream("QmTest".to_string(), "00ff".to_string(), 10);
+        assert_eq!(stat, Stat::from_record(&stat.encode()).unwrap());
+
+        // a record written before the digest was appended
+        let mut legacy = stat.encode();
+        legacy.truncate(legacy.len() - 5);
+        let decoded = Stat::from_record(&legacy).unwrap();
+        assert_eq!(("QmTest", "", 10), (decoded.hash.as_str(), decoded.digest.as_str(), decoded.st_size));
+
+        assert!(Stat::from_record(b"20201010101010").is_err());
+    }
 
     #[test]
     fn test_default_client() {
@@ -120,5 +399,38 @@ mod test {
         let uri = client.uri();
         assert_eq!("http://127.0.0.1:5001", uri);
     }
-}
 
+    #[test]
+    fn test_object_stat() {
+        let uri = mock_server("200 OK", r#"{"Hash":"QmTest","NumLinks":1,"BlockSize":62,"LinksSize":53,"DataSize":9,"CumulativeSize":1078}"#);
+        let stat = IpfsClient::new(&uri).object_stat("QmTest").unwrap();
+        assert_eq!("QmTest", stat.hash);
+        assert_eq!(1078, stat.cumulative_size);
+    }
+
+    #[test]
+    fn test_add_multipart() {
+        let uri = mock_server("200 OK", concat!(
+            r#"{"Name":"a.txt","Hash":"QmA","Size":"13"}"#, "\n",
+            r#"{"Name":"b.txt","Hash":"QmB","Size":"7"}"#, "\n",
+            r#"{"Name":"","Hash":"QmDir","Size":"120"}"#, "\n",
+        ));
+        let body = "--x\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nhello\r\n--x--\r\n";
+        let (stat, added) = IpfsClient::new(&uri).add_multipart("multipart/form-data; boundary=x", body.as_bytes()).unwrap();
+
+        assert_eq!("QmDir", stat.hash);
+        assert_eq!(20, stat.st_size);
+        assert_eq!(hex::encode(blake2b(32, &[], body.as_bytes()).as_bytes()), stat.digest);
+        assert_eq!(vec!["a.txt", "b.txt"], added.iter().map(|a| a.name.as_str()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_api_error() {
+        let uri = mock_server("500 Internal Server Error", r#"{"Message":"invalid path","Code":0,"Type":"error"}"#);
+        let err = IpfsClient::new(&uri).object_stat("bad").unwrap_err();
+        match err.kind {
+            MinerErrorKind::IpfsApi(e) => assert_eq!("invalid path", e.message),
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+}
@@ -1,2 +1,2 @@
 pub mod client;
-mod model;
\ No newline at end of file
+pub mod model;
\ No newline at end of file
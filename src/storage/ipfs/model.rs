@@ -1,12 +1,42 @@
 use serde::Deserialize;
+use std::fmt;
+use nix::sys::{statfs, statvfs};
 
+use crate::error::{MinerError, Result};
 
+
+#[derive(Serialize, Debug, Clone)]
 pub struct DiskInfo {
     pub free_space: u64,
     pub fstype: String,
     pub total_space: u64,
 }
 
+impl DiskInfo {
+    /// space of the file system holding `path`
+    pub fn of(path: &str) -> Result<DiskInfo> {
+        let vfs = statvfs::statvfs(path).map_err(|e| MinerError::msg(e))?;
+        let fs = statfs::statfs(path).map_err(|e| MinerError::msg(e))?;
+
+        let fstype = match fs.filesystem_type().0 as u64 {
+            0xef53 => "ext4".to_owned(),
+            0x5846_5342 => "xfs".to_owned(),
+            0x9123_683e => "btrfs".to_owned(),
+            0x2fc1_2fc1 => "zfs".to_owned(),
+            0x0102_1994 => "tmpfs".to_owned(),
+            0x794c_7630 => "overlayfs".to_owned(),
+            0x6969 => "nfs".to_owned(),
+            magic => format!("{:#x}", magic),
+        };
+
+        Ok(DiskInfo {
+            free_space: vfs.blocks_available() as u64 * vfs.fragment_size() as u64,
+            fstype,
+            total_space: vfs.blocks() as u64 * vfs.fragment_size() as u64,
+        })
+    }
+}
+
 
 pub struct Memory {
     pub memory: u64,
@@ -21,4 +51,103 @@ pub struct Runtime {
     pub numgoroutines: u64,
     pub os: String,
     pub version: String,
-}
\ No newline at end of file
+}
+
+
+/// error body returned by the ipfs http api on a non 2xx response
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct ApiError {
+    pub message: String,
+    pub code: i64,
+    #[serde(rename = "Type")]
+    pub typ: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ipfs api error({}): {}", self.code, self.message)
+    }
+}
+
+/// `/api/v0/add`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct AddResponse {
+    pub name: String,
+    pub hash: String,
+    pub size: String,
+}
+
+/// `/api/v0/pin/add`, `/api/v0/pin/rm`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct PinResponse {
+    #[serde(default)]
+    pub pins: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct PinType {
+    #[serde(rename = "Type")]
+    pub typ: String,
+}
+
+/// `/api/v0/pin/ls`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct PinLsResponse {
+    #[serde(default)]
+    pub keys: std::collections::HashMap<String, PinType>,
+}
+
+/// `/api/v0/object/stat`
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct ObjectStat {
+    pub hash: String,
+    pub num_links: u64,
+    pub block_size: u64,
+    pub links_size: u64,
+    pub data_size: u64,
+    pub cumulative_size: u64,
+}
+
+/// `/api/v0/files/stat`
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct FilesStat {
+    pub hash: String,
+    pub size: u64,
+    pub cumulative_size: u64,
+    pub blocks: u64,
+    #[serde(rename = "Type")]
+    pub typ: String,
+}
+
+/// one line of the `/api/v0/repo/gc` stream
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct RepoGcKey {
+    #[serde(rename = "/")]
+    pub cid: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct RepoGcResponse {
+    pub key: Option<RepoGcKey>,
+    pub error: Option<String>,
+}
+
+/// `/api/v0/repo/stat`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct RepoStat {
+    pub repo_size: u64,
+    pub storage_max: u64,
+    pub num_objects: u64,
+    pub repo_path: String,
+    pub version: String,
+}
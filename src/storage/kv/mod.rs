@@ -1,4 +1,5 @@
 pub mod rocksdb;
+pub mod order;
 
 #[derive(Debug)]
 pub struct MinerDbKey {
@@ -0,0 +1,561 @@
+use std::io;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use kvdb::{KeyValueDB, DBTransaction};
+use kvdb_rocksdb::Database;
+use lazy_static::lazy_static;
+use codec::{Encode, Decode};
+use chrono::{DateTime, Local, TimeZone, Duration};
+use std::convert::TryFrom;
+
+use crate::constants::{ORDER_COL, HASH_COL, UNPAID_COL, INFO_COL, CHAIN_COL, FILES_COL, MERKLE_COL, EXTEND_COL, USAGE_COL};
+use crate::storage::ipfs::client::Stat;
+use crate::util::merkle::Leaves;
+
+
+/// length of the upload time suffix of an order key, `%Y%m%d%H%M%S`
+pub const TIME_LEN: usize = 14;
+
+lazy_static! {
+    /// held while the reference and usage counters are read and written back
+    static ref COUNTERS: Mutex<()> = Mutex::new(());
+}
+
+/// current time in the format used in keys and values of the database
+pub fn now() -> String {
+    Local::now().format("%Y%m%d%H%M%S").to_string()
+}
+
+/// `time` moved `days` ahead, `None` when the result does not fit a date
+pub fn add_days<Tz: TimeZone>(time: DateTime<Tz>, days: u64) -> Option<DateTime<Tz>> {
+    // a chrono duration holds at most i64::MAX milliseconds
+    let seconds = i64::try_from(days).ok()?.checked_mul(86_400).filter(|s| *s <= i64::MAX / 1_000)?;
+    time.checked_add_signed(Duration::seconds(seconds))
+}
+
+/// key of an uploaded order: `address ++ hash ++ upload time`
+pub fn order_key(address: &str, hash: &str, time: &str) -> Vec<u8> {
+    [address, hash, time].concat().into_bytes()
+}
+
+/// key of the order info: `address ++ hash`
+pub fn info_key(address: &str, hash: &str) -> Vec<u8> {
+    [address, hash].concat().into_bytes()
+}
+
+/// One uploaded order as stored in the order column.
+#[derive(Debug)]
+pub struct Order {
+    pub address: String,
+    pub hash: String,
+    /// upload time, `%Y%m%d%H%M%S`
+    pub time: String,
+    pub stat: Stat,
+}
+
+impl Order {
+    /// split a `address ++ hash ++ time` key with the hash taken from the stored stat
+    fn parse(key: &[u8], stat: Stat) -> Option<Self> {
+        let key = std::str::from_utf8(key).ok()?;
+        if key.len() < TIME_LEN + stat.hash.len() {
+            return None;
+        }
+
+        let (rest, time) = key.split_at(key.len() - TIME_LEN);
+        if !rest.ends_with(stat.hash.as_str()) {
+            return None;
+        }
+        let address = &rest[..rest.len() - stat.hash.len()];
+
+        Some(Order {
+            address: address.to_string(),
+            hash: stat.hash.clone(),
+            time: time.to_string(),
+            stat,
+        })
+    }
+}
+
+/// Metadata added to an uploaded order, stored under `address ++ hash`.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub struct OrderInfo {
+    pub name: String,
+    pub label: String,
+    pub category: String,
+    pub describe: String,
+    pub days: u64,
+    /// expire time, `%Y%m%d%H%M%S`
+    pub expire: String,
+}
+
+pub fn get_info(db: &Database, address: &str, hash: &str) -> io::Result<Option<OrderInfo>> {
+    Ok(db.get(INFO_COL, &info_key(address, hash))?
+        .and_then(|value| OrderInfo::decode(&mut &value[..]).ok()))
+}
+
+/// One extension of the expiry of an order.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub struct Extension {
+    /// time of the extension, `%Y%m%d%H%M%S`
+    pub time: String,
+    pub days: u64,
+    /// price in plancks, a decimal string in json as it may not fit a json number
+    #[serde(serialize_with = "serialize_u128")]
+    pub price: u128,
+    pub previous_expire: String,
+    pub expire: String,
+}
+
+fn serialize_u128<S: serde::Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.to_string())
+}
+
+pub fn get_extensions(db: &Database, address: &str, hash: &str) -> io::Result<Vec<Extension>> {
+    Ok(db.get(EXTEND_COL, &info_key(address, hash))?
+        .and_then(|value| Vec::<Extension>::decode(&mut &value[..]).ok())
+        .unwrap_or_default())
+}
+
+/// Add `days` to the expiry of an order with order info, counted from now
+/// when it already expired, and append the extension to its history.
+/// Returns `None` when the order has no info yet.
+pub fn extend(db: &Database, address: &str, hash: &str, days: u64, price: u128) -> io::Result<Option<(OrderInfo, Extension)>> {
+    let mut info = match get_info(db, address, hash)? {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+
+    let now = Local::now();
+    let base = Local.datetime_from_str(&info.expire, "%Y%m%d%H%M%S").ok()
+        .filter(|expire| *expire > now)
+        .unwrap_or(now);
+    let expire = add_days(base, days)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} days do not fit a date", days)))?
+        .format("%Y%m%d%H%M%S").to_string();
+
+    let extension = Extension {
+        time: now.format("%Y%m%d%H%M%S").to_string(),
+        days,
+        price,
+        previous_expire: info.expire.clone(),
+        expire: expire.clone(),
+    };
+    info.days = info.days.saturating_add(days);
+    info.expire = expire;
+
+    let mut history = get_extensions(db, address, hash)?;
+    history.push(extension.clone());
+
+    let key = info_key(address, hash);
+    let mut batch = db.transaction();
+    batch.put(INFO_COL, &key, &info.encode());
+    batch.put(EXTEND_COL, &key, &history.encode());
+    db.write(batch)?;
+
+    Ok(Some((info, extension)))
+}
+
+/// A file of a directory upload.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub struct UploadedFile {
+    pub name: String,
+    pub hash: String,
+    pub size: u64,
+}
+
+/// files of a directory order, empty for a single upload
+pub fn get_files(db: &Database, address: &str, hash: &str) -> io::Result<Vec<UploadedFile>> {
+    Ok(db.get(FILES_COL, &info_key(address, hash))?
+        .and_then(|value| Vec::<UploadedFile>::decode(&mut &value[..]).ok())
+        .unwrap_or_default())
+}
+
+/// latest upload of `hash` by `address`
+pub fn find(db: &Database, address: &str, hash: &str) -> Option<Order> {
+    let prefix = info_key(address, hash);
+    orders(db, &prefix)
+        .filter(|o| o.address == address && o.hash == hash)
+        .max_by(|a, b| a.time.cmp(&b.time))
+}
+
+/// on-chain order id, if it is known
+pub fn get_order_id(db: &Database, address: &str, hash: &str) -> io::Result<Option<u64>> {
+    Ok(db.get(CHAIN_COL, &info_key(address, hash))?
+        .and_then(|value| u64::decode(&mut &value[..]).ok()))
+}
+
+/// number of orders holding `hash`, across all addresses
+pub fn references(db: &Database, hash: &str) -> io::Result<u64> {
+    Ok(db.get(HASH_COL, hash.as_bytes())?
+        .and_then(|value| std::str::from_utf8(&value).ok().and_then(|v| v.parse::<u64>().ok()))
+        .unwrap_or(0))
+}
+
+/// Result of removing an order from the database.
+#[derive(Serialize, Debug, Default)]
+pub struct Removed {
+    /// upload records removed
+    pub records: u64,
+    /// orders still holding the hash, 0 means it can be unpinned
+    pub references: u64,
+    pub order_id: Option<u64>,
+}
+
+/// Usage the stored orders may grow to, `None` is unlimited.
+#[derive(Debug, Clone, Copy)]
+pub struct StoreLimit {
+    /// bytes of the miner
+    pub capacity: u64,
+    /// bytes of the address
+    pub max_bytes: Option<u64>,
+    /// orders of the address
+    pub max_objects: Option<u64>,
+}
+
+/// Outcome of `store`, an order exceeding the limit is not written.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Stored {
+    Stored,
+    /// bytes the miner had left
+    CapacityExceeded(u64),
+    QuotaExceeded(String),
+}
+
+impl StoreLimit {
+    /// check `added` against the usage of the miner and of the address
+    fn check(&self, total: Usage, mine: Usage, added: Usage) -> Stored {
+        if total.bytes + added.bytes > self.capacity {
+            return Stored::CapacityExceeded(self.capacity.saturating_sub(total.bytes));
+        }
+        match (self.max_objects, self.max_bytes) {
+            (Some(max), _) if mine.objects + added.objects > max => {
+                Stored::QuotaExceeded(format!("{} of {} objects stored or uploading", mine.objects, max))
+            }
+            (_, Some(max)) if mine.bytes + added.bytes > max => {
+                Stored::QuotaExceeded(format!("{} of {} bytes stored or uploading", mine.bytes, max))
+            }
+            _ => Stored::Stored,
+        }
+    }
+}
+
+/// Record an upload added to ipfs as an order of `address`, together with
+/// the files of a directory and the leaves of a single file, in one transaction.
+/// The limit is checked under the counters lock, so concurrent uploads cannot
+/// pass it together.
+pub fn store(db: &Database, address: &str, stat: &Stat, files: &[UploadedFile], leaves: Option<&Leaves>, limit: &StoreLimit) -> io::Result<Stored> {
+    let _counters = COUNTERS.lock().unwrap();
+    let added = Usage { bytes: stat.st_size as u64, objects: 1 };
+    let stored = limit.check(usage(db, b""), usage(db, address.as_bytes()), added);
+    if stored != Stored::Stored {
+        return Ok(stored);
+    }
+    let mut batch = db.transaction();
+
+    batch.put(ORDER_COL, &order_key(address, &stat.hash, &now()), &stat.encode());
+
+    // count the orders holding the hash, it is unpinned when the last one is deleted
+    let references = references(db, &stat.hash)? + 1;
+    batch.put(HASH_COL, stat.hash.as_bytes(), references.to_string().as_bytes());
+    count_usage(db, &mut batch, address, added, true)?;
+
+    // add data hash pay flag
+    batch.put(UNPAID_COL, stat.hash.as_bytes(), b"");
+
+    if !files.is_empty() {
+        batch.put(FILES_COL, &info_key(address, &stat.hash), &files.encode());
+    }
+
+    // leaves to answer storage challenges, the user keeps the root
+    if let Some(leaves) = leaves {
+        batch.put(MERKLE_COL, stat.hash.as_bytes(), &leaves.encode());
+    }
+
+    db.write(batch)?;
+    Ok(Stored::Stored)
+}
+
+/// remove every record of the order of `address` for `hash` in one transaction
+pub fn remove(db: &Database, address: &str, hash: &str) -> io::Result<Removed> {
+    let _counters = COUNTERS.lock().unwrap();
+    let prefix = info_key(address, hash);
+    let records: Vec<_> = db.iter_with_prefix(ORDER_COL, &prefix)
+        .filter_map(|(key, value)| {
+            let stat = Stat::from_record(&value).ok()?;
+            Order::parse(&key, stat).filter(|o| o.address == address && o.hash == hash).map(|o| (key, o.stat.st_size as u64))
+        })
+        .collect();
+
+    let order_id = get_order_id(db, address, hash)?;
+    let references = references(db, hash)?.saturating_sub(records.len() as u64);
+
+    let mut batch = db.transaction();
+    for (key, _) in records.iter() {
+        batch.delete(ORDER_COL, key);
+    }
+    let freed = Usage {
+        bytes: records.iter().map(|(_, size)| size).sum(),
+        objects: records.len() as u64,
+    };
+    count_usage(db, &mut batch, address, freed, false)?;
+    batch.delete(INFO_COL, &prefix);
+    batch.delete(CHAIN_COL, &prefix);
+    batch.delete(FILES_COL, &prefix);
+    batch.delete(EXTEND_COL, &prefix);
+    if references == 0 {
+        batch.delete(HASH_COL, hash.as_bytes());
+        batch.delete(UNPAID_COL, hash.as_bytes());
+        batch.delete(MERKLE_COL, hash.as_bytes());
+    } else {
+        batch.put(HASH_COL, hash.as_bytes(), references.to_string().as_bytes());
+    }
+    db.write(batch)?;
+
+    Ok(Removed {
+        records: records.len() as u64,
+        references,
+        order_id,
+    })
+}
+
+/// merkle leaves recorded for the content `hash`
+pub fn get_leaves(db: &Database, hash: &str) -> io::Result<Option<Leaves>> {
+    Ok(db.get(MERKLE_COL, hash.as_bytes())?
+        .and_then(|value| Leaves::decode(&mut &value[..]).ok()))
+}
+
+/// whether the data hash pay flag is still set
+pub fn is_unpaid(db: &Database, hash: &str) -> io::Result<bool> {
+    Ok(db.get(UNPAID_COL, hash.as_bytes())?.is_some())
+}
+
+/// One entry of the order listing of an address.
+#[derive(Serialize, Debug)]
+pub struct OrderEntry {
+    pub hash: String,
+    pub size: u64,
+    pub time: String,
+    pub name: Option<String>,
+    pub label: Option<String>,
+    pub category: Option<String>,
+    pub expire: Option<String>,
+    pub paid: bool,
+}
+
+/// every order of `address` together with its info and pay state
+pub fn list(db: &Database, address: &str) -> io::Result<Vec<OrderEntry>> {
+    orders(db, address.as_bytes())
+        .filter(|o| o.address == address)
+        .map(|order| entry(db, order))
+        .collect()
+}
+
+/// the orders of every address, with their address
+pub fn list_all(db: &Database) -> io::Result<Vec<(String, OrderEntry)>> {
+    orders(db, b"")
+        .map(|order| Ok((order.address.clone(), entry(db, order)?)))
+        .collect()
+}
+
+fn entry(db: &Database, order: Order) -> io::Result<OrderEntry> {
+    let info = get_info(db, &order.address, &order.hash)?;
+    Ok(OrderEntry {
+        size: order.stat.st_size as u64,
+        time: order.time,
+        name: info.as_ref().map(|i| i.name.clone()),
+        label: info.as_ref().map(|i| i.label.clone()),
+        category: info.as_ref().map(|i| i.category.clone()),
+        expire: info.map(|i| i.expire),
+        paid: !is_unpaid(db, &order.hash)?,
+        hash: order.hash,
+    })
+}
+
+/// Set the expire time of an order, an order without info gets an empty one.
+pub fn set_expire(db: &Database, address: &str, hash: &str, expire: &str) -> io::Result<OrderInfo> {
+    let mut info = get_info(db, address, hash)?.unwrap_or_else(|| OrderInfo {
+        name: String::new(),
+        label: String::new(),
+        category: String::new(),
+        describe: String::new(),
+        days: 0,
+        expire: String::new(),
+    });
+    info.expire = expire.to_owned();
+
+    let mut batch = db.transaction();
+    batch.put(INFO_COL, &info_key(address, hash), &info.encode());
+    db.write(batch)?;
+    Ok(info)
+}
+
+/// Bytes and objects held by the stored orders.
+#[derive(Serialize, Debug, Default, Clone, Copy, Eq, PartialEq, Encode, Decode)]
+pub struct Usage {
+    pub bytes: u64,
+    pub objects: u64,
+}
+
+/// usage of `address`, an empty address sums up the whole miner
+pub fn usage(db: &Database, address: &[u8]) -> Usage {
+    db.get(USAGE_COL, address).ok().flatten()
+        .and_then(|value| Usage::decode(&mut &value[..]).ok())
+        .unwrap_or_default()
+}
+
+/// move the usage counters of `address` and of the miner by `change` in `batch`
+fn count_usage(db: &Database, batch: &mut DBTransaction, address: &str, change: Usage, added: bool) -> io::Result<()> {
+    for key in [address.as_bytes(), &b""[..]].iter() {
+        let current = usage(db, key);
+        let counted = if added {
+            Usage { bytes: current.bytes + change.bytes, objects: current.objects + change.objects }
+        } else {
+            Usage { bytes: current.bytes.saturating_sub(change.bytes), objects: current.objects.saturating_sub(change.objects) }
+        };
+        batch.put(USAGE_COL, key, &counted.encode());
+    }
+    Ok(())
+}
+
+/// Count the orders stored before the usage counters were kept, once.
+/// Returns whether the counters were built.
+pub fn init_usage(db: &Database) -> io::Result<bool> {
+    let _counters = COUNTERS.lock().unwrap();
+    if db.get(USAGE_COL, b"")?.is_some() {
+        return Ok(false);
+    }
+
+    let mut counted: HashMap<String, Usage> = HashMap::new();
+    for order in orders(db, b"") {
+        for key in [order.address.clone(), String::new()].iter() {
+            let usage = counted.entry(key.clone()).or_default();
+            usage.bytes += order.stat.st_size as u64;
+            usage.objects += 1;
+        }
+    }
+    counted.entry(String::new()).or_default();
+
+    let mut batch = db.transaction();
+    for (key, usage) in counted.iter() {
+        batch.put(USAGE_COL, key.as_bytes(), &usage.encode());
+    }
+    db.write(batch)?;
+    Ok(true)
+}
+
+/// all orders whose key starts with `prefix`, records that are not an order are skipped
+pub fn orders<'a>(db: &'a Database, prefix: &'a [u8]) -> impl Iterator<Item=Order> + 'a {
+    db.iter_with_prefix(ORDER_COL, prefix)
+        .filter_map(|(key, value)| {
+            let stat = Stat::from_record(&value).ok()?;
+            Order::parse(&key, stat)
+        })
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::Encode;
+    use kvdb_rocksdb::DatabaseConfig;
+    use tempdir::TempDir;
+    use crate::constants::META_COL;
+
+    #[test]
+    fn test_usage() {
+        let tempdir = TempDir::new("").unwrap();
+        let db = Database::open(&DatabaseConfig::with_columns(META_COL), tempdir.path().to_str().unwrap()).unwrap();
+
+        let mut batch = db.transaction();
+        for (address, hash, size) in &[("5Alice", "QmA", 10), ("5Alice", "QmB", 20), ("5Bob", "QmA", 10)] {
+            let stat = Stat::from_stream(hash.to_string(), String::new(), *size);
+            batch.put(ORDER_COL, &order_key(address, hash, &now()), &stat.encode());
+        }
+        // expire records share the column but are not counted
+        batch.put(ORDER_COL, ["QmA", now().as_str()].concat().as_bytes(), now().as_bytes());
+        db.write(batch).unwrap();
+
+        // orders written before the counters were kept
+        assert_eq!(Usage::default(), usage(&db, b""));
+        assert!(init_usage(&db).unwrap());
+        assert!(!init_usage(&db).unwrap());
+        assert_eq!(Usage { bytes: 30, objects: 2 }, usage(&db, b"5Alice"));
+        assert_eq!(Usage { bytes: 40, objects: 3 }, usage(&db, b""));
+
+        let limit = StoreLimit { capacity: 50, max_bytes: None, max_objects: Some(2) };
+        let stat = Stat::from_stream("QmC".to_string(), String::new(), 5);
+        assert_eq!(Stored::Stored, store(&db, "5Bob", &stat, &[], None, &limit).unwrap());
+        assert_eq!(Usage { bytes: 15, objects: 2 }, usage(&db, b"5Bob"));
+
+        // checked against the counters, a rejected order is not written
+        let stat = Stat::from_stream("QmD".to_string(), String::new(), 5);
+        assert!(matches!(store(&db, "5Bob", &stat, &[], None, &limit).unwrap(), Stored::QuotaExceeded(_)));
+        let stat = Stat::from_stream("QmD".to_string(), String::new(), 10);
+        assert_eq!(Stored::CapacityExceeded(5), store(&db, "5Alice", &stat, &[], None, &limit).unwrap());
+        assert_eq!(Usage { bytes: 45, objects: 4 }, usage(&db, b""));
+
+        remove(&db, "5Alice", "QmB").unwrap();
+        assert_eq!(Usage { bytes: 10, objects: 1 }, usage(&db, b"5Alice"));
+        assert_eq!(Usage { bytes: 25, objects: 3 }, usage(&db, b""));
+
+        let order = orders(&db, b"5Bob").next().unwrap();
+        assert_eq!(("5Bob", "QmA"), (order.address.as_str(), order.hash.as_str()));
+        assert_eq!(TIME_LEN, order.time.len());
+    }
+
+    #[test]
+    fn test_remove() {
+        let tempdir = TempDir::new("").unwrap();
+        let db = Database::open(&DatabaseConfig::with_columns(META_COL), tempdir.path().to_str().unwrap()).unwrap();
+
+        let mut batch = db.transaction();
+        for address in &["5Alice", "5Bob"] {
+            let stat = Stat::from_stream("QmA".to_string(), String::new(), 10);
+            batch.put(ORDER_COL, &order_key(address, "QmA", &now()), &stat.encode());
+        }
+        batch.put(HASH_COL, b"QmA", b"2");
+        db.write(batch).unwrap();
+
+        let removed = remove(&db, "5Alice", "QmA").unwrap();
+        assert_eq!((1, 1), (removed.records, removed.references));
+        assert!(find(&db, "5Alice", "QmA").is_none());
+
+        let removed = remove(&db, "5Bob", "QmA").unwrap();
+        assert_eq!((1, 0), (removed.records, removed.references));
+        assert!(db.get(HASH_COL, b"QmA").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_extend() {
+        let tempdir = TempDir::new("").unwrap();
+        let db = Database::open(&DatabaseConfig::with_columns(META_COL), tempdir.path().to_str().unwrap()).unwrap();
+
+        assert!(extend(&db, "5Alice", "QmA", 10, 1).unwrap().is_none());
+
+        // an expired order is extended from now
+        let info = OrderInfo {
+            name: "a".to_owned(),
+            label: String::new(),
+            category: String::new(),
+            describe: String::new(),
+            days: 1,
+            expire: "20200101000000".to_owned(),
+        };
+        let mut batch = db.transaction();
+        batch.put(INFO_COL, &info_key("5Alice", "QmA"), &info.encode());
+        db.write(batch).unwrap();
+
+        let (info, first) = extend(&db, "5Alice", "QmA", 10, 1).unwrap().unwrap();
+        assert_eq!(11, info.days);
+        assert!(first.expire > now());
+
+        let (info, second) = extend(&db, "5Alice", "QmA", 5, 1).unwrap().unwrap();
+        assert_eq!(first.expire, second.previous_expire);
+        assert_eq!(info.expire, second.expire);
+        assert_eq!(vec![first, second], get_extensions(&db, "5Alice", "QmA").unwrap());
+
+        assert!(extend(&db, "5Alice", "QmA", u64::MAX, 1).is_err());
+        assert!(add_days(Local::now(), i64::MAX as u64 / 86_400).is_none());
+    }
+}
@@ -1,5 +1,6 @@
 pub mod ipfs;
 pub mod kv;
+pub mod upload;
 
 
 pub trait Storage {
@@ -0,0 +1,275 @@
+//! Resumable upload sessions, kept on the miner disk until they are finalized.
+//!
+//! Every session is a `<id>.json` description next to the `<id>.part` data
+//! received so far, the size of the data file is the session offset. An open
+//! session reserves its full length against the capacity and the quotas.
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use chrono::Utc;
+
+use crate::error::{MinerError, MinerErrorKind, Result};
+use crate::util::data::HashReader;
+use crate::util::id::PasteID;
+
+
+/// seconds after the last write a session is considered abandoned
+pub const SESSION_TTL: i64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub address: String,
+    /// total bytes of the upload
+    pub length: u64,
+    /// hex blake2b-256 of the complete upload
+    pub digest: String,
+    pub created: i64,
+    pub updated: i64,
+}
+
+/// Bytes and sessions reserved by the open uploads.
+#[derive(Serialize, Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Reserved {
+    pub bytes: u64,
+    pub sessions: u64,
+}
+
+pub struct Uploads {
+    dir: PathBuf,
+    /// sessions a request is writing to
+    busy: Mutex<HashSet<String>>,
+}
+
+/// Exclusive access to a session, released when dropped.
+pub struct SessionLock<'a> {
+    uploads: &'a Uploads,
+    id: String,
+}
+
+impl Drop for SessionLock<'_> {
+    fn drop(&mut self) {
+        self.uploads.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Uploads {
+    pub fn new(dir: PathBuf) -> Self {
+        Uploads { dir, busy: Mutex::new(HashSet::new()) }
+    }
+
+    /// take the session `id` for a write, a second request on it is turned away
+    pub fn lock(&self, id: &str) -> Result<SessionLock> {
+        if !self.busy.lock().unwrap().insert(id.to_owned()) {
+            return Err(MinerError::new(MinerErrorKind::Conflict("upload is busy with another request".to_owned())));
+        }
+        Ok(SessionLock { uploads: self, id: id.to_owned() })
+    }
+
+    fn session_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    pub fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+
+    fn save(&self, session: &Session) -> Result<()> {
+        fs::write(self.session_path(&session.id), serde_json::to_vec(session)?)?;
+        Ok(())
+    }
+
+    pub fn create(&self, address: &str, length: u64, digest: &str) -> Result<Session> {
+        fs::create_dir_all(&self.dir)?;
+
+        let now = Utc::now().timestamp();
+        let session = Session {
+            id: PasteID::new(24).to_string(),
+            address: address.to_owned(),
+            length,
+            digest: digest.to_lowercase(),
+            created: now,
+            updated: now,
+        };
+
+        File::create(self.data_path(&session.id))?;
+        self.save(&session)?;
+        Ok(session)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Session>> {
+        // ids are generated base62, anything else can not be a session
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Ok(None);
+        }
+        match fs::read(self.session_path(id)) {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// every open session, unreadable descriptions are skipped
+    pub fn sessions(&self) -> Result<Vec<Session>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut sessions = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |e| e != "json") {
+                continue;
+            }
+            if let Some(Ok(Some(session))) = path.file_stem().and_then(|s| s.to_str()).map(|id| self.get(id)) {
+                sessions.push(session);
+            }
+        }
+        Ok(sessions)
+    }
+
+    /// lengths of the open sessions of `address`, or of every address when `None`,
+    /// leaving out the session `skip`
+    pub fn reserved(&self, address: Option<&str>, skip: Option<&str>) -> Result<Reserved> {
+        Ok(self.sessions()?.iter()
+            .filter(|s| address.map_or(true, |a| s.address == a) && skip.map_or(true, |id| s.id != id))
+            .fold(Reserved::default(), |reserved, s| Reserved {
+                bytes: reserved.bytes + s.length,
+                sessions: reserved.sessions + 1,
+            }))
+    }
+
+    /// bytes received so far
+    pub fn offset(&self, session: &Session) -> Result<u64> {
+        Ok(fs::metadata(self.data_path(&session.id))?.len())
+    }
+
+    /// Append a chunk starting at `offset`, which must be the current offset.
+    ///
+    /// The chunk is rolled back when it runs past the session length or its
+    /// blake2b-256 differs from `digest`. Returns the new offset.
+    pub fn append<R: Read>(&self, session: &mut Session, offset: u64, chunk: R, digest: &str) -> Result<u64> {
+        let _lock = self.lock(&session.id)?;
+        let current = self.offset(session)?;
+        if offset != current {
+            return Err(MinerError::new(MinerErrorKind::Conflict(format!("upload offset is {}, not {}", current, offset))));
+        }
+
+        let remaining = session.length.saturating_sub(current);
+        let mut reader = HashReader::new(chunk.take(remaining + 1));
+        let mut file = OpenOptions::new().append(true).open(self.data_path(&session.id))?;
+        let written = io::copy(&mut reader, &mut file)?;
+
+        if written > remaining {
+            file.set_len(current)?;
+            return Err(MinerError::new(MinerErrorKind::Conflict(format!("chunk runs past the upload length {}", session.length))));
+        }
+        if reader.summary().digest() != digest {
+            file.set_len(current)?;
+            return Err(crate::auth::unauthorized("chunk does not match the signed body hash"));
+        }
+        file.flush()?;
+
+        session.updated = Utc::now().timestamp();
+        self.save(session)?;
+        Ok(current + written)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<()> {
+        for path in &[self.data_path(id), self.session_path(id)] {
+            if let Err(e) = fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// remove sessions without a write for `SESSION_TTL`, returns how many were removed
+    pub fn remove_stale(&self) -> Result<usize> {
+        if !self.dir.exists() {
+            return Ok(0);
+        }
+
+        let now = Utc::now().timestamp();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |e| e != "json") {
+                continue;
+            }
+            let id = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(id) => id.to_owned(),
+                None => continue,
+            };
+
+            // unreadable descriptions are abandoned as well
+            let stale = match self.get(&id) {
+                Ok(Some(session)) => now - session.updated > SESSION_TTL,
+                _ => true,
+            };
+            if stale {
+                self.remove(&id)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+    use blake2_rfc::blake2b::blake2b;
+
+    fn digest(data: &[u8]) -> String {
+        hex::encode(blake2b(32, &[], data).as_bytes())
+    }
+
+    #[test]
+    fn test_append() {
+        let tempdir = TempDir::new("").unwrap();
+        let uploads = Uploads::new(tempdir.path().to_path_buf());
+
+        let mut session = uploads.create("5Alice", 10, &digest(b"0123456789")).unwrap();
+        assert_eq!(5, uploads.append(&mut session, 0, &b"01234"[..], &digest(b"01234")).unwrap());
+
+        // a resent chunk does not match the offset
+        assert!(uploads.append(&mut session, 0, &b"01234"[..], &digest(b"01234")).is_err());
+        // a chunk past the length is rolled back
+        assert!(uploads.append(&mut session, 5, &b"567890"[..], &digest(b"567890")).is_err());
+        assert_eq!(5, uploads.offset(&session).unwrap());
+
+        assert_eq!(10, uploads.append(&mut session, 5, &b"56789"[..], &digest(b"56789")).unwrap());
+        assert_eq!(b"0123456789".to_vec(), fs::read(uploads.data_path(&session.id)).unwrap());
+
+        uploads.remove(&session.id).unwrap();
+        assert!(uploads.get(&session.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reserved() {
+        let tempdir = TempDir::new("").unwrap();
+        let uploads = Uploads::new(tempdir.path().to_path_buf());
+
+        let first = uploads.create("5Alice", 10, &digest(b"0123456789")).unwrap();
+        uploads.create("5Alice", 20, &digest(b"")).unwrap();
+        uploads.create("5Bob", 5, &digest(b"")).unwrap();
+
+        assert_eq!(Reserved { bytes: 35, sessions: 3 }, uploads.reserved(None, None).unwrap());
+        assert_eq!(Reserved { bytes: 20, sessions: 1 }, uploads.reserved(Some("5Alice"), Some(&first.id)).unwrap());
+
+        // a session written to is busy until the write is done
+        let lock = uploads.lock(&first.id).unwrap();
+        assert!(uploads.lock(&first.id).is_err());
+        let mut session = first.clone();
+        assert!(uploads.append(&mut session, 0, &b"01234"[..], &digest(b"01234")).is_err());
+        drop(lock);
+        assert_eq!(5, uploads.append(&mut session, 0, &b"01234"[..], &digest(b"01234")).unwrap());
+    }
+}
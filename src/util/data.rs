@@ -0,0 +1,267 @@
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use blake2_rfc::blake2b::Blake2b;
+use rocket::http::ContentType;
+
+
+struct Summary {
+    hasher: Blake2b,
+    size: u64,
+    /// the stream ended with another digest than the expected one
+    mismatch: bool,
+    /// the stream grew past its limit
+    exceeded: bool,
+}
+
+/// Shared view on the bytes that went through a `HashReader`,
+/// still readable after the reader itself was moved into a request body.
+#[derive(Clone)]
+pub struct SummaryHandle(Arc<Mutex<Summary>>);
+
+impl SummaryHandle {
+    /// number of bytes read so far
+    pub fn size(&self) -> u64 {
+        self.0.lock().unwrap().size
+    }
+
+    /// hex encoded blake2b-256 digest of the bytes read so far
+    pub fn digest(&self) -> String {
+        let hasher = self.0.lock().unwrap().hasher.clone();
+        hex::encode(hasher.finalize().as_bytes())
+    }
+
+    /// whether the stream was failed for not matching the expected digest
+    pub fn mismatched(&self) -> bool {
+        self.0.lock().unwrap().mismatch
+    }
+
+    /// whether the stream was failed for growing past its limit
+    pub fn exceeded(&self) -> bool {
+        self.0.lock().unwrap().exceeded
+    }
+}
+
+/// Hash and count a stream while it is being read.
+pub struct HashReader<R> {
+    inner: R,
+    summary: SummaryHandle,
+    expected: Option<String>,
+    limit: u64,
+}
+
+impl<R: Read> HashReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashReader {
+            inner,
+            summary: SummaryHandle(Arc::new(Mutex::new(Summary { hasher: Blake2b::new(32), size: 0, mismatch: false, exceeded: false }))),
+            expected: None,
+            limit: u64::MAX,
+        }
+    }
+
+    /// Fail the stream as soon as it grows past `limit` bytes, instead of
+    /// cutting it short and handing a truncated body to the consumer.
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Fail the end of the stream when its digest is not `digest`, so the
+    /// consumer never sees a complete body that was not signed.
+    pub fn expect(mut self, digest: &str) -> Self {
+        self.expected = Some(digest.to_lowercase());
+        self
+    }
+
+    pub fn summary(&self) -> SummaryHandle {
+        self.summary.clone()
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        let mut summary = self.summary.0.lock().unwrap();
+        summary.hasher.update(&buf[..n]);
+        summary.size += n as u64;
+
+        if summary.size > self.limit {
+            summary.exceeded = true;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "stream is larger than its limit"));
+        }
+        if n == 0 && !buf.is_empty() {
+            if let Some(ref expected) = self.expected {
+                let digest = hex::encode(summary.hasher.clone().finalize().as_bytes());
+                if &digest != expected {
+                    summary.mismatch = true;
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "stream does not match the expected digest"));
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+
+/// Parse a single `bytes=` range of a `Range` header against the content size.
+///
+/// Returns the inclusive `(start, end)` of the range, `Ok(None)` when the header
+/// should be ignored (other units, multiple ranges, bad syntax) and `Err(())`
+/// when the range is not satisfiable.
+pub fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+
+    let mut bounds = spec.splitn(2, '-');
+    let (start, end) = match (bounds.next(), bounds.next()) {
+        (Some(start), Some(end)) => (start.trim(), end.trim()),
+        _ => return Ok(None),
+    };
+
+    if start.is_empty() {
+        // suffix range, the last `end` bytes
+        let suffix = match end.parse::<u64>() {
+            Ok(suffix) => suffix,
+            Err(_) => return Ok(None),
+        };
+        if suffix == 0 || size == 0 {
+            return Err(());
+        }
+        return Ok(Some((size.saturating_sub(suffix), size - 1)));
+    }
+
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return Ok(None),
+    };
+    let end = match end {
+        "" => size.saturating_sub(1),
+        end => match end.parse::<u64>() {
+            Ok(end) if end >= start => end.min(size.saturating_sub(1)),
+            _ => return Ok(None),
+        }
+    };
+    if start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Guess the content type from the first bytes of the content. Markup a
+/// browser would run scripts from, html, svg and xml, is served as binary so
+/// uploaded content never executes on the origin of the miner.
+pub fn sniff_content_type(head: &[u8]) -> ContentType {
+    let magic: &[(&[u8], ContentType)] = &[
+        (&b"\x89PNG\r\n\x1a\n"[..], ContentType::PNG),
+        (&b"\xff\xd8\xff"[..], ContentType::JPEG),
+        (&b"GIF87a"[..], ContentType::GIF),
+        (&b"GIF89a"[..], ContentType::GIF),
+        (&b"%PDF-"[..], ContentType::PDF),
+        (&b"PK\x03\x04"[..], ContentType::ZIP),
+        (&b"\x1f\x8b"[..], ContentType::GZIP),
+        (&b"OggS"[..], ContentType::OGG),
+        (&b"fLaC"[..], ContentType::FLAC),
+        (&b"ID3"[..], ContentType::MPEG),
+        (&b"\x1a\x45\xdf\xa3"[..], ContentType::WEBM),
+        (&b"\x00asm"[..], ContentType::WASM),
+        (&b"wOFF"[..], ContentType::WOFF),
+        (&b"wOF2"[..], ContentType::WOFF2),
+        (&b"BM"[..], ContentType::BMP),
+    ];
+    if let Some((_, content_type)) = magic.iter().find(|(m, _)| head.starts_with(m)) {
+        return content_type.clone();
+    }
+
+    if head.len() >= 12 && head.starts_with(b"RIFF") {
+        match &head[8..12] {
+            b"WEBP" => return ContentType::WEBP,
+            b"WAVE" => return ContentType::WAV,
+            _ => {}
+        }
+    }
+    if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        return if head.len() >= 12 && &head[8..12] == b"qt  " { ContentType::MOV } else { ContentType::MP4 };
+    }
+
+    match std::str::from_utf8(head) {
+        Ok(text) if !text.contains('\0') => {
+            if text.trim_start().starts_with('<') {
+                ContentType::Binary
+            } else {
+                ContentType::Plain
+            }
+        }
+        _ => ContentType::Binary,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use blake2_rfc::blake2b::blake2b;
+    use rocket::http::ContentType;
+    use super::{HashReader, parse_range, sniff_content_type};
+
+    #[test]
+    fn test_hash_reader() {
+        let mut reader = HashReader::new(&b"hello ipse"[..]);
+        let summary = reader.summary();
+
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+
+        assert_eq!(10, summary.size());
+        assert_eq!(64, summary.digest().len());
+        assert_eq!(hex::encode(blake2b(32, &[], b"hello ipse").as_bytes()), summary.digest());
+    }
+
+    #[test]
+    fn test_expect_digest() {
+        let digest = hex::encode(blake2b(32, &[], b"hello ipse").as_bytes());
+
+        let mut out = vec![];
+        assert!(HashReader::new(&b"hello ipse"[..]).expect(&digest).read_to_end(&mut out).is_ok());
+
+        let mut reader = HashReader::new(&b"hello ipsf"[..]).expect(&digest);
+        let summary = reader.summary();
+        assert!(reader.read_to_end(&mut vec![]).is_err());
+        assert!(summary.mismatched());
+    }
+
+    #[test]
+    fn test_limit() {
+        assert!(HashReader::new(&b"hello ipse"[..]).limit(10).read_to_end(&mut vec![]).is_ok());
+
+        let mut reader = HashReader::new(&b"hello ipse"[..]).limit(9);
+        let summary = reader.summary();
+        assert!(reader.read_to_end(&mut vec![]).is_err());
+        assert!(summary.exceeded());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(Ok(Some((0, 99))), parse_range("bytes=0-99", 1000));
+        assert_eq!(Ok(Some((500, 999))), parse_range("bytes=500-", 1000));
+        assert_eq!(Ok(Some((900, 999))), parse_range("bytes=-100", 1000));
+        assert_eq!(Ok(Some((990, 999))), parse_range("bytes=990-2000", 1000));
+        assert_eq!(Ok(None), parse_range("bytes=0-1,5-6", 1000));
+        assert_eq!(Ok(None), parse_range("items=0-1", 1000));
+        assert_eq!(Ok(None), parse_range("bytes=9-1", 1000));
+        assert_eq!(Err(()), parse_range("bytes=1000-", 1000));
+        assert_eq!(Err(()), parse_range("bytes=-0", 1000));
+    }
+
+    #[test]
+    fn test_sniff_content_type() {
+        assert_eq!(ContentType::PNG, sniff_content_type(b"\x89PNG\r\n\x1a\n\x00\x00"));
+        assert_eq!(ContentType::PDF, sniff_content_type(b"%PDF-1.4"));
+        assert_eq!(ContentType::Binary, sniff_content_type(b"  <!DOCTYPE html><html>"));
+        assert_eq!(ContentType::Binary, sniff_content_type(b"<svg onload=\"alert(1)\">"));
+        assert_eq!(ContentType::Plain, sniff_content_type(b"hello ipse"));
+        assert_eq!(ContentType::Binary, sniff_content_type(&[0, 159, 146, 150]));
+    }
+}
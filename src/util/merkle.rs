@@ -0,0 +1,211 @@
+//! Merkle tree over the fixed size chunks of a content.
+//!
+//! Leaves are the blake2b-256 of each `CHUNK_SIZE` chunk, a node is the
+//! blake2b-256 of its two children, the last node of an odd level is paired
+//! with itself. Empty content has the single leaf of the empty chunk.
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use blake2_rfc::blake2b::{blake2b, Blake2b};
+use codec::{Encode, Decode};
+
+
+/// bytes of one leaf chunk
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+
+pub type Hash = [u8; 32];
+
+/// The leaves of a content and its size, kept to answer challenges.
+#[derive(Encode, Decode, Debug, Clone, Eq, PartialEq)]
+pub struct Leaves {
+    pub size: u64,
+    pub leaves: Vec<Hash>,
+}
+
+impl Leaves {
+    pub fn root(&self) -> Hash {
+        root(&self.leaves)
+    }
+}
+
+pub fn hash(data: &[u8]) -> Hash {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(blake2b(32, &[], data).as_bytes());
+    out
+}
+
+fn node(left: &Hash, right: &Hash) -> Hash {
+    hash(&[&left[..], &right[..]].concat())
+}
+
+fn parent_level(level: &[Hash]) -> Vec<Hash> {
+    level.chunks(2).map(|pair| node(&pair[0], pair.get(1).unwrap_or(&pair[0]))).collect()
+}
+
+pub fn root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return hash(&[]);
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = parent_level(&level);
+    }
+    level[0]
+}
+
+/// siblings of the leaf `index` from the bottom to the top of the tree
+pub fn path(leaves: &[Hash], index: usize) -> Vec<Hash> {
+    let mut siblings = vec![];
+    let mut level = leaves.to_vec();
+    let mut index = index;
+    while level.len() > 1 {
+        let sibling = if index % 2 == 0 { index + 1 } else { index - 1 };
+        siblings.push(*level.get(sibling).unwrap_or(&level[index]));
+        level = parent_level(&level);
+        index /= 2;
+    }
+    siblings
+}
+
+/// root of the tree holding `leaf` at `index` with the given `path`
+pub fn root_from_path(leaf: &Hash, index: usize, path: &[Hash]) -> Hash {
+    let mut index = index;
+    path.iter().fold(*leaf, |current, sibling| {
+        let next = if index % 2 == 0 { node(&current, sibling) } else { node(sibling, &current) };
+        index /= 2;
+        next
+    })
+}
+
+/// leaf indexes picked by a challenge seed, derived as blake2b(seed, i)
+pub fn challenge_indexes(seed: &[u8], count: usize, leaves: usize) -> Vec<usize> {
+    (0..count as u64).map(|i| {
+        let digest = blake2b(32, seed, &i.to_le_bytes());
+        let mut head = [0u8; 8];
+        head.copy_from_slice(&digest.as_bytes()[..8]);
+        (u64::from_le_bytes(head) % leaves.max(1) as u64) as usize
+    }).collect()
+}
+
+
+struct State {
+    chunk: Blake2b,
+    chunk_len: u64,
+    size: u64,
+    leaves: Vec<Hash>,
+}
+
+impl State {
+    fn finish_chunk(&mut self) {
+        let chunk = std::mem::replace(&mut self.chunk, Blake2b::new(32));
+        let mut leaf = [0u8; 32];
+        leaf.copy_from_slice(chunk.finalize().as_bytes());
+        self.leaves.push(leaf);
+        self.chunk_len = 0;
+    }
+}
+
+/// Shared view on the leaves of the bytes that went through a `MerkleReader`.
+#[derive(Clone)]
+pub struct LeavesHandle(Arc<Mutex<State>>);
+
+impl LeavesHandle {
+    /// leaves of the bytes read so far, including the pending last chunk
+    pub fn leaves(&self) -> Leaves {
+        let state = self.0.lock().unwrap();
+        let mut leaves = state.leaves.clone();
+        if state.chunk_len > 0 || leaves.is_empty() {
+            let mut leaf = [0u8; 32];
+            leaf.copy_from_slice(state.chunk.clone().finalize().as_bytes());
+            leaves.push(leaf);
+        }
+        Leaves { size: state.size, leaves }
+    }
+}
+
+/// Collect the chunk leaves of a stream while it is being read.
+pub struct MerkleReader<R> {
+    inner: R,
+    state: LeavesHandle,
+}
+
+impl<R: Read> MerkleReader<R> {
+    pub fn new(inner: R) -> Self {
+        MerkleReader {
+            inner,
+            state: LeavesHandle(Arc::new(Mutex::new(State {
+                chunk: Blake2b::new(32),
+                chunk_len: 0,
+                size: 0,
+                leaves: vec![],
+            }))),
+        }
+    }
+
+    pub fn handle(&self) -> LeavesHandle {
+        self.state.clone()
+    }
+}
+
+impl<R: Read> Read for MerkleReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        let mut state = self.state.0.lock().unwrap();
+        let mut rest = &buf[..n];
+        while !rest.is_empty() {
+            let take = ((CHUNK_SIZE - state.chunk_len) as usize).min(rest.len());
+            state.chunk.update(&rest[..take]);
+            state.chunk_len += take as u64;
+            state.size += take as u64;
+            rest = &rest[take..];
+            if state.chunk_len == CHUNK_SIZE {
+                state.finish_chunk();
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+/// read a whole stream and return its leaves
+pub fn leaves<R: Read>(reader: R) -> io::Result<Leaves> {
+    let mut reader = MerkleReader::new(reader);
+    let handle = reader.handle();
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(handle.leaves())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_leaves() {
+        let data = vec![7u8; (CHUNK_SIZE * 2 + 10) as usize];
+        let leaves = leaves(&data[..]).unwrap();
+
+        assert_eq!(data.len() as u64, leaves.size);
+        assert_eq!(3, leaves.leaves.len());
+        assert_eq!(hash(&data[..CHUNK_SIZE as usize]), leaves.leaves[0]);
+        assert_eq!(hash(&data[(CHUNK_SIZE * 2) as usize..]), leaves.leaves[2]);
+
+        assert_eq!(vec![hash(&[])], super::leaves(&b""[..]).unwrap().leaves);
+    }
+
+    #[test]
+    fn test_path() {
+        let leaves: Vec<Hash> = (0u8..5).map(|i| hash(&[i])).collect();
+        let root = root(&leaves);
+
+        for index in 0..leaves.len() {
+            let path = path(&leaves, index);
+            assert_eq!(root, root_from_path(&leaves[index], index, &path));
+        }
+        assert_ne!(root, root_from_path(&hash(b"other"), 1, &path(&leaves, 1)));
+
+        let indexes = challenge_indexes(b"seed", 8, leaves.len());
+        assert_eq!(indexes, challenge_indexes(b"seed", 8, leaves.len()));
+        assert!(indexes.iter().all(|i| *i < leaves.len()));
+    }
+}
@@ -1,4 +1,5 @@
 pub mod file;
 pub mod id;
 pub mod data;
+pub mod merkle;
 pub mod net;
\ No newline at end of file
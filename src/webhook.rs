@@ -0,0 +1,378 @@
+//! Order event webhooks.
+//!
+//! Events are queued in the database right after the state change they
+//! announce, in a write of their own, and delivered later as json posts
+//! signed by the miner pair. A failed delivery is retried with exponential
+//! backoff and dead-lettered after `webhooks.max_attempts` attempts.
+//! Every outcome is written as soon as it is known, so a crash resends at
+//! most the delivery in flight.
+use std::time::{Duration, Instant};
+use chrono::{Local, TimeZone, Utc};
+use codec::{Encode, Decode};
+use kvdb::KeyValueDB;
+use kvdb_rocksdb::Database;
+use sp_core::{sr25519::Pair, Pair as PairT};
+
+use crate::constants::{INFO_COL, WEBHOOK_COL};
+use crate::crypto::Ss58Codec;
+use crate::error::Result;
+use crate::settings::{Settings, Webhooks};
+use crate::storage::kv::order::{self, OrderInfo};
+use crate::util::id::PasteID;
+
+
+pub const EVENT_HEADER: &str = "X-Ipse-Event";
+pub const DELIVERY_HEADER: &str = "X-Ipse-Delivery";
+pub const MINER_HEADER: &str = "X-Ipse-Miner";
+/// hex sr25519 signature of the miner over the body
+pub const SIGNATURE_HEADER: &str = "X-Ipse-Signature";
+
+/// seconds before the first retry, doubled on every attempt
+const BACKOFF_BASE: i64 = 30;
+/// longest wait between two attempts
+const BACKOFF_MAX: i64 = 6 * 60 * 60;
+/// most deliveries sent in one run
+const BATCH: usize = 100;
+/// seconds a run keeps starting deliveries, the rest waits for the next run
+const RUN_TIME: u64 = 60;
+/// seconds a receiver has to answer
+const SEND_TIMEOUT: u64 = 10;
+
+const QUEUE_PREFIX: &str = "queue:";
+const DEAD_PREFIX: &str = "dead:";
+const EXPIRING_PREFIX: &str = "expiring:";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Event {
+    Uploaded,
+    MetadataAdded,
+    Confirmed,
+    Extended,
+    ExpiringSoon,
+    Expired,
+    Deleted,
+}
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Uploaded => "order.uploaded",
+            Event::MetadataAdded => "order.metadata_added",
+            Event::Confirmed => "order.confirmed",
+            Event::Extended => "order.extended",
+            Event::ExpiringSoon => "order.expiring_soon",
+            Event::Expired => "order.expired",
+            Event::Deleted => "order.deleted",
+        }
+    }
+}
+
+/// One event to post to one url.
+#[derive(Serialize, Encode, Decode, Debug, Clone, Eq, PartialEq)]
+pub struct Delivery {
+    pub id: String,
+    pub url: String,
+    pub event: String,
+    /// json body, signed when it is sent
+    #[serde(serialize_with = "serialize_body")]
+    pub body: Vec<u8>,
+    pub attempts: u32,
+    /// unix timestamp of the next attempt
+    pub next_attempt: i64,
+    pub last_error: String,
+    pub created: i64,
+}
+
+fn serialize_body<S: serde::Serializer>(body: &Vec<u8>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(body))
+}
+
+fn queue_key(next_attempt: i64, id: &str) -> Vec<u8> {
+    // zero padded so the queue iterates in attempt order
+    format!("{}{:012}:{}", QUEUE_PREFIX, next_attempt.max(0), id).into_bytes()
+}
+
+fn dead_key(id: &str) -> Vec<u8> {
+    [DEAD_PREFIX, id].concat().into_bytes()
+}
+
+/// seconds to wait after `attempts` failed attempts
+pub fn backoff(attempts: u32) -> i64 {
+    BACKOFF_BASE.saturating_mul(1i64 << attempts.min(20)).min(BACKOFF_MAX)
+}
+
+/// Queue `event` of an order for every configured url. Called once the state
+/// change is written, the event is lost when the miner stops in between.
+pub fn emit(db: &Database, settings: &Settings, event: Event, address: &str, hash: &str, data: serde_json::Value) -> Result<()> {
+    if settings.webhooks.urls.is_empty() {
+        return Ok(());
+    }
+
+    let now = Utc::now().timestamp();
+    let body = serde_json::to_vec(&serde_json::json!({
+        "event": event.name(),
+        "time": now,
+        "address": address,
+        "hash": hash,
+        "data": data,
+    }))?;
+
+    let mut batch = db.transaction();
+    for url in settings.webhooks.urls.iter() {
+        let delivery = Delivery {
+            id: PasteID::new(16).to_string(),
+            url: url.clone(),
+            event: event.name().to_owned(),
+            body: body.clone(),
+            attempts: 0,
+            next_attempt: now,
+            last_error: String::new(),
+            created: now,
+        };
+        batch.put(WEBHOOK_COL, &queue_key(now, &delivery.id), &delivery.encode());
+    }
+    db.write(batch)?;
+    Ok(())
+}
+
+/// queue an event, logging instead of failing the state change it announces
+pub fn emit_logged(db: &Database, settings: &Settings, event: Event, address: &str, hash: &str, data: serde_json::Value) {
+    if let Err(e) = emit(db, settings, event, address, hash, data) {
+        log::error!("queue webhook {} of {}: {}", event.name(), hash, e);
+    }
+}
+
+/// deliveries waiting for an attempt and dead-lettered ones
+pub fn pending(db: &Database) -> (Vec<Delivery>, Vec<Delivery>) {
+    let decode = |prefix: &str| db.iter_with_prefix(WEBHOOK_COL, prefix.as_bytes())
+        .filter_map(|(_, value)| Delivery::decode(&mut &value[..]).ok())
+        .collect::<Vec<_>>();
+    (decode(QUEUE_PREFIX), decode(DEAD_PREFIX))
+}
+
+fn due(db: &Database, now: i64) -> Vec<(Vec<u8>, Delivery)> {
+    db.iter_with_prefix(WEBHOOK_COL, QUEUE_PREFIX.as_bytes())
+        .filter_map(|(key, value)| Delivery::decode(&mut &value[..]).ok().map(|d| (key.to_vec(), d)))
+        .take_while(|(_, delivery)| delivery.next_attempt <= now)
+        .take(BATCH)
+        .collect()
+}
+
+fn send(client: &reqwest::blocking::Client, pair: &Pair, delivery: &Delivery) -> std::result::Result<(), String> {
+    let signature = hex::encode(pair.sign(&delivery.body).0.as_ref());
+    let response = client.post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, delivery.event.as_str())
+        .header(DELIVERY_HEADER, delivery.id.as_str())
+        .header(MINER_HEADER, pair.public().to_ss58check())
+        .header(SIGNATURE_HEADER, signature)
+        .body(delivery.body.clone())
+        .send()
+        .map_err(|e| e.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("receiver answered {}", response.status()))
+    }
+}
+
+/// Outcome of a delivery run.
+#[derive(Serialize, Debug, Default)]
+pub struct Delivered {
+    pub sent: usize,
+    pub retried: usize,
+    pub dead: usize,
+}
+
+/// Send the due deliveries for up to `RUN_TIME` seconds, retrying the failed
+/// ones later or dead-lettering them after `webhooks.max_attempts` attempts.
+pub fn deliver(db: &Database, webhooks: &Webhooks, pair: &Pair) -> Result<Delivered> {
+    let now = Utc::now().timestamp();
+    let due = due(db, now);
+    if due.is_empty() {
+        return Ok(Delivered::default());
+    }
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(SEND_TIMEOUT))
+        .build()?;
+    let started = Instant::now();
+    let mut delivered = Delivered::default();
+    for (key, mut delivery) in due {
+        if started.elapsed() >= Duration::from_secs(RUN_TIME) {
+            break;
+        }
+        let outcome = send(&client, pair, &delivery);
+
+        let mut batch = db.transaction();
+        batch.delete(WEBHOOK_COL, &key);
+        match outcome {
+            Ok(()) => delivered.sent += 1,
+            Err(e) => {
+                delivery.attempts += 1;
+                delivery.last_error = e;
+                if delivery.attempts >= webhooks.max_attempts {
+                    log::error!("webhook {} to {} dead-lettered: {}", delivery.id, delivery.url, delivery.last_error);
+                    batch.put(WEBHOOK_COL, &dead_key(&delivery.id), &delivery.encode());
+                    delivered.dead += 1;
+                } else {
+                    log::warn!("webhook {} to {} failed: {}", delivery.id, delivery.url, delivery.last_error);
+                    delivery.next_attempt = Utc::now().timestamp() + backoff(delivery.attempts - 1);
+                    batch.put(WEBHOOK_COL, &queue_key(delivery.next_attempt, &delivery.id), &delivery.encode());
+                    delivered.retried += 1;
+                }
+            }
+        }
+        db.write(batch)?;
+    }
+    Ok(delivered)
+}
+
+/// Queue `order.expiring_soon` once for every order expiring within
+/// `webhooks.expiring_hours`, again when its expiry was extended since.
+pub fn notify_expiring(db: &Database, settings: &Settings) -> Result<usize> {
+    if settings.webhooks.urls.is_empty() {
+        return Ok(0);
+    }
+
+    let now = order::now();
+    let horizon = (Local::now() + chrono::Duration::hours(settings.webhooks.expiring_hours as i64))
+        .format("%Y%m%d%H%M%S").to_string();
+
+    let expiring: Vec<_> = db.iter(INFO_COL)
+        .filter_map(|(key, value)| OrderInfo::decode(&mut &value[..]).ok().map(|info| (key, info)))
+        .filter(|(_, info)| info.expire.as_str() > now.as_str() && info.expire.as_str() <= horizon.as_str())
+        .collect();
+
+    // forget the announcements of orders that are gone
+    let mut batch = db.transaction();
+    for (marker, _) in db.iter_with_prefix(WEBHOOK_COL, EXPIRING_PREFIX.as_bytes()) {
+        if db.get(INFO_COL, &marker[EXPIRING_PREFIX.len()..])?.is_none() {
+            batch.delete(WEBHOOK_COL, &marker);
+        }
+    }
+    db.write(batch)?;
+
+    let mut notified = 0;
+    for (key, info) in expiring {
+        let marker = [EXPIRING_PREFIX.as_bytes(), &key[..]].concat();
+        if db.get(WEBHOOK_COL, &marker)?.as_ref().map(|v| &v[..]) == Some(info.expire.as_bytes()) {
+            continue;
+        }
+        let order = match order::orders(db, &key).next() {
+            Some(order) => order,
+            None => continue,
+        };
+
+        let expire_ts = Local.datetime_from_str(&info.expire, "%Y%m%d%H%M%S").map(|t| t.timestamp()).ok();
+        emit(db, settings, Event::ExpiringSoon, &order.address, &order.hash, serde_json::json!({
+            "expire": info.expire,
+            "expire_timestamp": expire_ts,
+        }))?;
+
+        let mut batch = db.transaction();
+        batch.put(WEBHOOK_COL, &marker, info.expire.as_bytes());
+        db.write(batch)?;
+        notified += 1;
+    }
+    Ok(notified)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use kvdb_rocksdb::DatabaseConfig;
+    use tempdir::TempDir;
+    use crate::constants::META_COL;
+
+    /// a receiver answering one post with `status`
+    fn receiver(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let (mut line, mut length) = (String::new(), 0);
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+                line.clear();
+            }
+            reader.read_exact(&mut vec![0; length]).unwrap();
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+        });
+
+        format!("http://{}", addr)
+    }
+
+    /// an url nobody listens on
+    fn refused() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    fn enqueue(db: &Database, id: &str, url: String, attempts: u32) {
+        let delivery = Delivery {
+            id: id.to_owned(),
+            url,
+            event: Event::Uploaded.name().to_owned(),
+            body: b"{}".to_vec(),
+            attempts,
+            next_attempt: 0,
+            last_error: String::new(),
+            created: 0,
+        };
+        let mut batch = db.transaction();
+        batch.put(WEBHOOK_COL, &queue_key(0, id), &delivery.encode());
+        db.write(batch).unwrap();
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(30, backoff(0));
+        assert_eq!(120, backoff(2));
+        assert_eq!(BACKOFF_MAX, backoff(30));
+    }
+
+    #[test]
+    fn test_queue_key_order() {
+        assert!(queue_key(99, "b") < queue_key(100, "a"));
+    }
+
+    #[test]
+    fn test_deliver() {
+        let tempdir = TempDir::new("").unwrap();
+        let db = Database::open(&DatabaseConfig::with_columns(META_COL), tempdir.path().to_str().unwrap()).unwrap();
+        let webhooks = Webhooks { max_attempts: 2, ..Webhooks::default() };
+        let (pair, _) = Pair::generate();
+
+        enqueue(&db, "sent", receiver("200 OK"), 0);
+        enqueue(&db, "retried", receiver("500 Internal Server Error"), 0);
+        enqueue(&db, "dead", refused(), 1);
+
+        let delivered = deliver(&db, &webhooks, &pair).unwrap();
+        assert_eq!((1, 1, 1), (delivered.sent, delivered.retried, delivered.dead));
+
+        let (queued, dead) = pending(&db);
+        assert_eq!(1, queued.len());
+        assert_eq!("retried", queued[0].id);
+        assert_eq!(1, queued[0].attempts);
+        assert_eq!("receiver answered 500 Internal Server Error", queued[0].last_error);
+        assert!(queued[0].next_attempt >= Utc::now().timestamp() + backoff(0) - 1);
+
+        assert_eq!(1, dead.len());
+        assert_eq!("dead", dead[0].id);
+        assert_eq!(2, dead[0].attempts);
+
+        // the retry is not due yet
+        let delivered = deliver(&db, &webhooks, &pair).unwrap();
+        assert_eq!((0, 0, 0), (delivered.sent, delivered.retried, delivered.dead));
+    }
+}